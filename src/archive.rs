@@ -0,0 +1,597 @@
+use crate::metadata::FileMeta;
+use crate::ratelimit::Throttled;
+use crate::{
+    _discover, _hash_reader, _load_files, _pb_update, _relative, _temp_path, runs, BackupEntry,
+    CopyOptions, Discovered, FileEntry, FileSize, HashingReader,
+};
+use colored::Colorize;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use indicatif::{HumanCount, MultiProgress, ProgressBar, ProgressStyle};
+use indicatif_log_bridge::LogWrapper;
+use log::{error, info};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// How the files of a backup are laid out in the destination.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// A mirror of the source tree made of plain files
+    #[default]
+    Dir,
+    /// One or more tar archives
+    Tar,
+}
+
+/// Compression applied to tar archives.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+}
+
+/// Returns the value stored in `Backups.compression` for backups made with `opts`, or `None`
+/// for plain directory backups.
+pub fn format_name(opts: &CopyOptions) -> Option<String> {
+    match (opts.format, opts.compression) {
+        (Format::Dir, _) => None,
+        (Format::Tar, None) => Some("tar".to_string()),
+        (Format::Tar, Some(Compression::Gzip)) => Some("tar.gz".to_string()),
+    }
+}
+
+/// Whether a backup with the given `Backups.compression` value is stored in archives.
+pub fn is_archive(format: Option<&str>) -> bool {
+    matches!(format, Some("tar") | Some("tar.gz"))
+}
+
+/// Counts the bytes written through it, so members can be located in the uncompressed
+/// tar stream.
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Where tar archives are written, either plain or gzip compressed.
+enum Sink {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl Sink {
+    fn finish(self, fsync: bool) -> io::Result<()> {
+        let file = match self {
+            Sink::Plain(v) => v.into_inner()?,
+            Sink::Gzip(v) => v.finish()?.into_inner()?,
+        };
+        if fsync {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Plain(v) => v.write(buf),
+            Sink::Gzip(v) => v.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(v) => v.flush(),
+            Sink::Gzip(v) => v.flush(),
+        }
+    }
+}
+
+/// An archive that is being written. It's written to a temporary file which is renamed into
+/// place once every archive of the backup is complete.
+struct ArchiveWriter {
+    path: PathBuf,
+    builder: tar::Builder<CountingWriter<Sink>>,
+}
+
+impl ArchiveWriter {
+    fn create(path: PathBuf, compression: Option<Compression>) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(_temp_path(&path))?);
+        let sink = match compression {
+            None => Sink::Plain(file),
+            Some(Compression::Gzip) => {
+                Sink::Gzip(GzEncoder::new(file, flate2::Compression::default()))
+            }
+        };
+        Ok(Self {
+            path,
+            builder: tar::Builder::new(CountingWriter {
+                inner: sink,
+                count: 0,
+            }),
+        })
+    }
+
+    /// Offset of the next member in the uncompressed tar stream.
+    fn offset(&self) -> u64 {
+        self.builder.get_ref().count
+    }
+
+    /// Completes the temporary file and returns the path it's renamed to later.
+    fn finish(self, fsync: bool) -> io::Result<PathBuf> {
+        let writer = self.builder.into_inner()?;
+        writer.inner.finish(fsync)?;
+        Ok(self.path)
+    }
+}
+
+/// Returns the path of the `part`th archive of a backup. Parts are only numbered if the
+/// backup is split into several archives.
+fn _archive_path(dest: &Path, name: &OsStr, opts: &CopyOptions, part: usize) -> PathBuf {
+    let ext = match opts.compression {
+        None => "tar",
+        Some(Compression::Gzip) => "tar.gz",
+    };
+    let name = name.to_string_lossy();
+    match opts.split_size {
+        Some(_) => dest.join(format!("{}.part{:04}.{}", name, part, ext)),
+        None => dest.join(format!("{}.{}", name, ext)),
+    }
+}
+
+/// Removes the archives of `name` in `dest` that weren't `written` by the latest run, such as
+/// the extra parts left over when an earlier run was split into more archives.
+fn _remove_stale_archives(dest: &Path, name: &OsStr, written: &[PathBuf]) -> io::Result<()> {
+    let name = name.to_string_lossy();
+    for entry in fs::read_dir(dest)? {
+        let path = entry?.path();
+        let Some(file_name) = path.file_name().and_then(|v| v.to_str()) else {
+            continue;
+        };
+        let Some(rest) = file_name
+            .strip_prefix(name.as_ref())
+            .and_then(|v| v.strip_prefix('.'))
+        else {
+            continue;
+        };
+        let ext = match rest.strip_prefix("part") {
+            Some(v)
+                if v.get(..4)
+                    .is_some_and(|n| n.bytes().all(|b| b.is_ascii_digit())) =>
+            {
+                v[4..].strip_prefix('.')
+            }
+            Some(_) => None,
+            None => Some(rest),
+        };
+        if matches!(ext, Some("tar" | "tar.gz")) && !written.contains(&path) {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads an archive, keeping track of the position in the uncompressed tar stream so it can
+/// skip ahead to the members it's asked for.
+struct MemberReader {
+    inner: Source,
+    pos: u64,
+}
+
+/// Where tar archives are read from, either plain or gzip compressed.
+enum Source {
+    Plain(BufReader<File>),
+    Gzip(GzDecoder<BufReader<File>>),
+}
+
+impl MemberReader {
+    fn open(path: &Path, format: &str) -> io::Result<Self> {
+        let file = BufReader::new(File::open(path)?);
+        let inner = match format {
+            "tar.gz" => Source::Gzip(GzDecoder::new(file)),
+            _ => Source::Plain(file),
+        };
+        Ok(Self { inner, pos: 0 })
+    }
+
+    /// Moves to `offset` in the uncompressed stream. Plain archives are seeked, compressed ones
+    /// have to be decompressed up to there.
+    fn skip_to(&mut self, offset: u64) -> io::Result<()> {
+        let n = offset
+            .checked_sub(self.pos)
+            .ok_or_else(|| io::Error::other("the member overlaps the one before it"))?;
+        match &mut self.inner {
+            Source::Plain(v) => v.seek_relative(n as i64)?,
+            Source::Gzip(v) => {
+                if io::copy(&mut v.take(n), &mut io::sink())? != n {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+        }
+        self.pos = offset;
+        Ok(())
+    }
+}
+
+impl Read for MemberReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = match &mut self.inner {
+            Source::Plain(v) => v.read(buf)?,
+            Source::Gzip(v) => v.read(buf)?,
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Streams the source tree into one or more tar archives in `dest_str`, recording the
+/// archive, offset and hash of every member in the catalog. Returns `true` on failure.
+pub fn create(
+    conn: &Connection,
+    opts: &CopyOptions,
+    id: u64,
+    source_str: &Path,
+    dest_str: &Path,
+) -> bool {
+    let source_name = source_str.iter().next_back().unwrap().to_owned();
+    let timer = Instant::now();
+    let run_id = runs::start(conn, id).unwrap();
+    let source = match fs::read_dir(source_str) {
+        Ok(d) => d,
+        Err(e) => {
+            let err = format!("{} (\"{}\")", e, source_str.display());
+            eprintln!("Error: {}", err);
+            runs::finish(conn, run_id, timer.elapsed(), 0, 0, 0, &[err]).unwrap();
+            return true;
+        }
+    };
+
+    let multi = MultiProgress::new();
+    let logger = colog::default_builder().build();
+    let _ = LogWrapper::new(multi.clone(), logger).try_init();
+
+    let mut files = Vec::new();
+    _discover(source, |discovered| match discovered {
+        Discovered::File(entry, meta) => files.push((entry.path(), meta)),
+        Discovered::FdLimit => error!("Too many file handles open, some files will be missing."),
+    });
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    let total: u64 = files.iter().map(|(_, meta)| meta.size).sum();
+
+    let pb = multi.add(ProgressBar::new(total));
+    pb.set_style(
+        ProgressStyle::with_template(
+            "{spinner:.green} [{elapsed_precise}] [{bar:50.cyan/blue}] {bytes}/{total_bytes} ({eta})",
+        )
+        .unwrap()
+        .progress_chars("#>-"),
+    );
+    let t = _pb_update(pb.clone());
+
+    let tx = conn.unchecked_transaction().unwrap();
+    tx.execute("DELETE FROM Files WHERE backup_id = ?1", [id as i64])
+        .unwrap();
+
+    let mut error_list = Vec::new();
+    let mut part = 0;
+    let mut finished = Vec::new();
+    let mut archive: Option<ArchiveWriter> = None;
+    let result = (|| -> io::Result<()> {
+        for (path, meta) in &files {
+            if archive
+                .as_ref()
+                .is_some_and(|v| opts.split_size.is_some_and(|max| v.offset() >= max))
+            {
+                finished.push(archive.take().unwrap().finish(opts.fsync)?);
+                part += 1;
+            }
+            if archive.is_none() {
+                let path = _archive_path(dest_str, &source_name, opts, part);
+                archive = Some(ArchiveWriter::create(path, opts.compression)?);
+            }
+            let writer = archive.as_mut().unwrap();
+
+            info!(
+                "{} \"{}\" ({})",
+                "Archiving".green().bold(),
+                path.display(),
+                FileSize::from(meta.size).to_string().bold()
+            );
+            let (file, fs_meta) = match File::open(path).and_then(|f| Ok((f.metadata()?, f))) {
+                Ok((m, f)) => (f, m),
+                Err(e) => {
+                    let err = format!(
+                        "Couldn't copy {:#?} because of error: {e}. Skipping\n",
+                        path
+                    );
+                    error!("{}", err);
+                    error_list.push(err);
+                    continue;
+                }
+            };
+
+            let member = Path::new(&source_name).join(_relative(path, source_str));
+            let mut header = tar::Header::new_gnu();
+            header.set_metadata(&fs_meta);
+            let offset = writer.offset();
+            let mut reader = HashingReader::new(Throttled(file.take(fs_meta.len())));
+            writer
+                .builder
+                .append_data(&mut header, &member, &mut reader)?;
+
+            _record(
+                &tx,
+                id,
+                path,
+                &dest_str.join(&member),
+                &reader.finish(),
+                &FileMeta::from(&fs_meta),
+                &writer.path,
+                offset,
+            )
+            .map_err(io::Error::other)?;
+            pb.inc(meta.size);
+        }
+        if let Some(archive) = archive.take() {
+            finished.push(archive.finish(opts.fsync)?);
+        }
+        // The archives of the last run are only replaced once every part was written, so
+        // they keep matching the catalog if this run fails.
+        for path in &finished {
+            fs::rename(_temp_path(path), path)?;
+        }
+        Ok(())
+    })();
+    drop(archive);
+    pb.finish();
+    t.join().unwrap();
+    multi.remove(&pb);
+
+    if let Err(e) = result {
+        let err = format!(
+            "Couldn't write the archive: {} (\"{}\")",
+            e,
+            dest_str.display()
+        );
+        eprintln!("{} {}", "Error:".red().bold(), err);
+        error_list.push(err);
+        drop(tx);
+        for i in 0..=part {
+            let _ = fs::remove_file(_temp_path(&_archive_path(dest_str, &source_name, opts, i)));
+        }
+        runs::finish(
+            conn,
+            run_id,
+            timer.elapsed(),
+            total,
+            files.len(),
+            0,
+            &error_list,
+        )
+        .unwrap();
+        return true;
+    }
+    tx.commit().unwrap();
+
+    if let Err(e) = _remove_stale_archives(dest_str, &source_name, &finished) {
+        let err = format!("Couldn't remove the archives of an earlier run: {e}\n");
+        error!("{}", err);
+        error_list.push(err);
+    }
+
+    let (verified, failed) = verify(conn, id, dest_str);
+    error_list.extend(failed);
+
+    let elapsed = timer.elapsed();
+    runs::finish(
+        conn,
+        run_id,
+        elapsed,
+        total,
+        files.len(),
+        verified,
+        &error_list,
+    )
+    .unwrap();
+
+    println!(
+        "\n{} {} files into {} archives {}{}{} in {} {}{}{}",
+        "Archived".green().bold(),
+        HumanCount(verified as u64),
+        part + 1,
+        "(".truecolor(150, 150, 150),
+        FileSize::from(total).to_string().truecolor(150, 150, 150),
+        ")".truecolor(150, 150, 150),
+        crate::_format_duration(elapsed),
+        "(".truecolor(150, 150, 150),
+        error_list.len().to_string().truecolor(150, 150, 150),
+        " errors)".truecolor(150, 150, 150),
+    );
+    !error_list.is_empty()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn _record(
+    conn: &Connection,
+    id: u64,
+    from: &Path,
+    to: &Path,
+    sha256: &str,
+    meta: &FileMeta,
+    archive: &Path,
+    offset: u64,
+) -> rusqlite::Result<usize> {
+    conn.execute(
+        "INSERT OR REPLACE INTO Files
+        (backup_id, source, dest, sha256, size, mtime, ctime, mode, inode, kind, archive, offset)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        (
+            id as i64,
+            from.display().to_string(),
+            to.display().to_string(),
+            sha256,
+            meta.size as i64,
+            meta.mtime,
+            meta.ctime,
+            meta.mode,
+            meta.inode.map(|v| v as i64),
+            meta.kind.as_str(),
+            archive.display().to_string(),
+            offset as i64,
+        ),
+    )
+}
+
+/// Calls `on_member` with the catalog entry and contents of every tracked member of the
+/// archives of backup `id`, in archive order. Members are located by their recorded offset,
+/// so the archives are only read up to the last tracked member. Tracked files that couldn't
+/// be found are returned along with the reason.
+fn _for_each_member(
+    conn: &Connection,
+    id: u64,
+    format: &str,
+    dest: &Path,
+    mut on_member: impl FnMut(&FileEntry, &mut dyn Read) -> io::Result<()>,
+) -> Vec<(FileEntry, String)> {
+    let mut archives: HashMap<PathBuf, Vec<(u64, FileEntry)>> = HashMap::new();
+    let mut missing = Vec::new();
+    for entry in _load_files(conn, id).unwrap() {
+        match (entry.archive.clone(), entry.offset) {
+            (Some(archive), Some(offset)) => {
+                archives.entry(archive).or_default().push((offset, entry));
+            }
+            _ => missing.push((entry, "not stored in an archive".to_string())),
+        }
+    }
+
+    let mut archives: Vec<_> = archives.into_iter().collect();
+    archives.sort_by(|a, b| a.0.cmp(&b.0));
+    for (path, mut members) in archives {
+        members.sort_by_key(|v| v.0);
+        let mut reader = match MemberReader::open(&path, format) {
+            Ok(v) => v,
+            Err(e) => {
+                let reason = format!("{} (\"{}\")", e, path.display());
+                missing.extend(members.into_iter().map(|v| (v.1, reason.clone())));
+                continue;
+            }
+        };
+        for (offset, entry) in members {
+            let result = (|| -> io::Result<()> {
+                reader.skip_to(offset)?;
+                let mut archive = tar::Archive::new(&mut reader);
+                let mut member = archive
+                    .entries()?
+                    .next()
+                    .ok_or_else(|| io::Error::other("missing from the archive"))??;
+                if *member.path()? != *_relative(&entry.to, dest) {
+                    return Err(io::Error::other("another member is stored at its offset"));
+                }
+                on_member(&entry, &mut member)
+            })();
+            if let Err(e) = result {
+                missing.push((entry, e.to_string()));
+            }
+        }
+    }
+    missing
+}
+
+/// Verifies the members of the archives of backup `id` against the hashes in the catalog.
+/// Returns how many were verified and the errors for the rest.
+pub fn verify(conn: &Connection, id: u64, dest: &Path) -> (usize, Vec<String>) {
+    let format = crate::_load_backup(conn, id)
+        .unwrap()
+        .and_then(|v| v.compression)
+        .unwrap_or_default();
+    let mut verified = 0;
+    let mut errors = Vec::new();
+    let failed = _for_each_member(conn, id, &format, dest, |entry, data| {
+        info!("{} \"{}\"", "Verifying".green().bold(), entry.to.display());
+        if _hash_reader(data)? == entry.sha256 {
+            verified += 1;
+        } else {
+            let err = format!("{:#?} doesn't match its hash\n", entry.to);
+            error!("{}", err);
+            errors.push(err);
+        }
+        Ok(())
+    });
+    errors.extend(failed.into_iter().map(|(entry, reason)| {
+        let err = format!("Couldn't verify {:#?}: {}\n", entry.to, reason);
+        error!("{}", err);
+        err
+    }));
+    (verified, errors)
+}
+
+/// Extracts the archives of `backup` back to the source paths of their members. Every
+/// member is verified while it's extracted and written atomically.
+pub fn restore(conn: &Connection, backup: &BackupEntry, opts: &CopyOptions) {
+    let multi = MultiProgress::new();
+    let logger = colog::default_builder().build();
+    let _ = LogWrapper::new(multi.clone(), logger).try_init();
+
+    let format = backup.compression.clone().unwrap_or_default();
+    let mut restored = 0u64;
+    let mut errors = Vec::new();
+    let failed = _for_each_member(conn, backup.id, &format, &backup.to, |entry, data| {
+        info!(
+            "{} \"{}\"",
+            "Restoring".green().bold(),
+            entry.from.display()
+        );
+        if let Some(parent) = entry.from.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp = _temp_path(&entry.from);
+        let result = (|| -> io::Result<()> {
+            let mut reader = HashingReader::new(Throttled(data));
+            let mut file = File::create(&temp)?;
+            io::copy(&mut reader, &mut file)?;
+            if reader.finish() != entry.sha256 {
+                return Err(io::Error::other("the archived copy doesn't match its hash"));
+            }
+            if opts.fsync {
+                file.sync_all()?;
+            }
+            fs::rename(&temp, &entry.from)
+        })();
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temp);
+            let err = format!("Couldn't restore {:#?} because of error: {e}", entry.from);
+            error!("{}", err);
+            errors.push(err);
+        } else {
+            restored += 1;
+        }
+        Ok(())
+    });
+    for (entry, reason) in failed {
+        error!("Couldn't restore {:#?}: {}", entry.from, reason);
+        errors.push(reason);
+    }
+
+    println!(
+        "{} {} files. ({} errors occured)",
+        "Restored".green().bold(),
+        HumanCount(restored),
+        HumanCount(errors.len() as u64),
+    );
+}
@@ -0,0 +1,423 @@
+//! Export and import of the catalog, so it can be moved between machines.
+//!
+//! The catalog is exported as JSON, either as a single document:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "backups": [
+//!     {
+//!       "id": 16354831419357049950,
+//!       "source": "/home/user/docs",
+//!       "dest": "/mnt/disk",
+//!       "compression": null,
+//!       "files": [
+//!         {
+//!           "source": "/home/user/docs/notes.txt",
+//!           "dest": "/mnt/disk/docs/notes.txt",
+//!           "sha256": "e3b0c442...",
+//!           "size": 5,
+//!           "mtime": 1729260000000000000,
+//!           "kind": "file"
+//!         }
+//!       ],
+//!       "runs": [
+//!         {
+//!           "started": 1729260000000,
+//!           "finished": 1729260001000,
+//!           "duration_ms": 1000,
+//!           "bytes": 5,
+//!           "file_count": 1,
+//!           "copied_count": 1,
+//!           "error_count": 0,
+//!           "errors": [],
+//!           "host": "workstation"
+//!         }
+//!       ]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! or as NDJSON, where the first line is `{"version":1}` and every following line is one
+//! backup object. File fields other than `source`, `dest` and `sha256` are optional and
+//! left out when unknown. Times of files are in nanoseconds and times of runs in
+//! milliseconds since the Unix epoch, like in `backups.db`.
+
+use crate::runs;
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+/// Version of the export format. Bumped whenever a change would break older importers.
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Catalog {
+    pub version: u32,
+    pub backups: Vec<BackupRecord>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Header {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct BackupRecord {
+    pub id: u64,
+    pub source: String,
+    pub dest: String,
+    pub compression: Option<String>,
+    /// The command reaching the peer, for backups sent with --remote.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+    /// Every source of backups that cover more than one directory.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<String>,
+    #[serde(default)]
+    pub files: Vec<FileRecord>,
+    #[serde(default)]
+    pub runs: Vec<RunRecord>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct FileRecord {
+    pub source: String,
+    pub dest: String,
+    pub sha256: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ctime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inode: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct RunRecord {
+    pub started: i64,
+    pub finished: Option<i64>,
+    pub duration_ms: Option<u64>,
+    pub bytes: u64,
+    pub file_count: u64,
+    pub copied_count: u64,
+    pub error_count: u64,
+    #[serde(default)]
+    pub errors: Vec<String>,
+    pub host: String,
+}
+
+#[derive(Debug)]
+pub enum CatalogError {
+    /// The export was written by a newer version of hardcpy.
+    UnsupportedVersion(u32),
+    /// A backup's id is already used by a different backup in the database.
+    IdCollision {
+        id: u64,
+        existing: (String, String),
+        imported: (String, String),
+    },
+    Io(io::Error),
+    Json(serde_json::Error),
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for CatalogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CatalogError::UnsupportedVersion(v) => write!(
+                f,
+                "The catalog uses format version {} but this version of hardcpy only supports up to {}. Please update hardcpy.",
+                v, FORMAT_VERSION
+            ),
+            CatalogError::IdCollision {
+                id,
+                existing,
+                imported,
+            } => write!(
+                f,
+                "Backup {} ({} -> {}) can't be imported because its id is already used by {} -> {}. Nothing was imported.",
+                id, imported.0, imported.1, existing.0, existing.1
+            ),
+            CatalogError::Io(e) => write!(f, "{}", e),
+            CatalogError::Json(e) => write!(f, "Couldn't parse the catalog: {}", e),
+            CatalogError::Sqlite(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for CatalogError {
+    fn from(value: io::Error) -> Self {
+        CatalogError::Io(value)
+    }
+}
+
+impl From<serde_json::Error> for CatalogError {
+    fn from(value: serde_json::Error) -> Self {
+        CatalogError::Json(value)
+    }
+}
+
+impl From<rusqlite::Error> for CatalogError {
+    fn from(value: rusqlite::Error) -> Self {
+        CatalogError::Sqlite(value)
+    }
+}
+
+/// What [`import`] changed.
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportSummary {
+    pub backups_added: usize,
+    pub backups_merged: usize,
+    pub files: usize,
+    pub runs: usize,
+}
+
+/// Reads every backup in the catalog along with its files and runs.
+pub fn read(conn: &Connection) -> Result<Catalog, CatalogError> {
+    let mut stmt =
+        conn.prepare("SELECT id, source, dest, compression, remote FROM Backups ORDER BY id")?;
+    let mut backups = stmt
+        .query_map((), |row| {
+            Ok(BackupRecord {
+                id: row.get::<usize, i64>(0)? as u64,
+                source: row.get(1)?,
+                dest: row.get(2)?,
+                compression: row.get(3)?,
+                remote: row.get(4)?,
+                sources: Vec::new(),
+                files: Vec::new(),
+                runs: Vec::new(),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut sources =
+        conn.prepare("SELECT source FROM Sources WHERE backup_id = ?1 ORDER BY rowid")?;
+    let mut stmt = conn.prepare(
+        "SELECT source, dest, sha256, size, mtime, ctime, mode, inode, kind, archive, offset
+        FROM Files WHERE backup_id = ?1 ORDER BY dest",
+    )?;
+    for backup in &mut backups {
+        backup.sources = sources
+            .query_map([backup.id as i64], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        backup.files = stmt
+            .query_map([backup.id as i64], |row| {
+                Ok(FileRecord {
+                    source: row.get(0)?,
+                    dest: row.get(1)?,
+                    sha256: row.get(2)?,
+                    size: row.get::<usize, Option<i64>>(3)?.map(|v| v as u64),
+                    mtime: row.get(4)?,
+                    ctime: row.get(5)?,
+                    mode: row.get(6)?,
+                    inode: row.get::<usize, Option<i64>>(7)?.map(|v| v as u64),
+                    kind: row.get(8)?,
+                    archive: row.get(9)?,
+                    offset: row.get::<usize, Option<i64>>(10)?.map(|v| v as u64),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        backup.runs = runs::list(conn, Some(backup.id), i64::MAX as usize)?
+            .into_iter()
+            .rev()
+            .map(|run| RunRecord {
+                started: run.started,
+                finished: run.finished,
+                duration_ms: run.duration.map(|v| v.as_millis() as u64),
+                bytes: run.bytes,
+                file_count: run.file_count,
+                copied_count: run.copied_count,
+                error_count: run.error_count,
+                errors: run.errors,
+                host: run.host,
+            })
+            .collect();
+    }
+
+    Ok(Catalog {
+        version: FORMAT_VERSION,
+        backups,
+    })
+}
+
+/// Writes the catalog to `writer`, as a single JSON document or as NDJSON.
+pub fn export(
+    conn: &Connection,
+    writer: &mut impl Write,
+    ndjson: bool,
+) -> Result<(), CatalogError> {
+    let catalog = read(conn)?;
+    if ndjson {
+        serde_json::to_writer(
+            &mut *writer,
+            &Header {
+                version: catalog.version,
+            },
+        )?;
+        writeln!(writer)?;
+        for backup in &catalog.backups {
+            serde_json::to_writer(&mut *writer, backup)?;
+            writeln!(writer)?;
+        }
+    } else {
+        serde_json::to_writer_pretty(&mut *writer, &catalog)?;
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Parses an export written by [`export`] in either format.
+pub fn parse(reader: impl BufRead) -> Result<Catalog, CatalogError> {
+    let mut values = serde_json::Deserializer::from_reader(reader).into_iter::<serde_json::Value>();
+    let first = match values.next() {
+        Some(v) => v?,
+        None => {
+            return Ok(Catalog {
+                version: FORMAT_VERSION,
+                backups: Vec::new(),
+            })
+        }
+    };
+
+    let catalog = if first.get("backups").is_some() {
+        serde_json::from_value::<Catalog>(first)?
+    } else {
+        let header = serde_json::from_value::<Header>(first)?;
+        let backups = values
+            .map(|v| Ok(serde_json::from_value::<BackupRecord>(v?)?))
+            .collect::<Result<Vec<_>, CatalogError>>()?;
+        Catalog {
+            version: header.version,
+            backups,
+        }
+    };
+    if catalog.version > FORMAT_VERSION {
+        return Err(CatalogError::UnsupportedVersion(catalog.version));
+    }
+    Ok(catalog)
+}
+
+/// Merges `catalog` into the database.
+///
+/// A backup whose id is already used by the same source and destination is merged into the
+/// existing one. If the id is used by a different backup, nothing is imported, since ids are
+/// derived from the source and destination and a renumbered backup couldn't be found again.
+/// Files that are already tracked keep their local entries, and runs that were already
+/// recorded are skipped.
+pub fn import(conn: &Connection, catalog: &Catalog) -> Result<ImportSummary, CatalogError> {
+    let mut summary = ImportSummary::default();
+    let tx = conn.unchecked_transaction()?;
+
+    for backup in &catalog.backups {
+        let id = backup.id;
+        let existing: Option<(String, String)> = tx
+            .query_row(
+                "SELECT source, dest FROM Backups WHERE id = ?1",
+                [id as i64],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        match existing {
+            Some((source, dest)) if source == backup.source && dest == backup.dest => {
+                summary.backups_merged += 1;
+            }
+            Some(existing) => {
+                return Err(CatalogError::IdCollision {
+                    id,
+                    existing,
+                    imported: (backup.source.clone(), backup.dest.clone()),
+                });
+            }
+            None => {
+                tx.execute(
+                    "INSERT INTO Backups (id, source, dest, compression, remote)
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+                    (
+                        id as i64,
+                        &backup.source,
+                        &backup.dest,
+                        &backup.compression,
+                        &backup.remote,
+                    ),
+                )?;
+                summary.backups_added += 1;
+            }
+        }
+
+        for source in &backup.sources {
+            tx.execute(
+                "INSERT OR IGNORE INTO Sources (backup_id, source) VALUES (?1, ?2)",
+                (id as i64, source),
+            )?;
+        }
+
+        for file in &backup.files {
+            summary.files += tx.execute(
+                "INSERT OR IGNORE INTO Files
+                (backup_id, source, dest, sha256, size, mtime, ctime, mode, inode, kind, archive, offset)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                (
+                    id as i64,
+                    &file.source,
+                    &file.dest,
+                    &file.sha256,
+                    file.size.map(|v| v as i64),
+                    file.mtime,
+                    file.ctime,
+                    file.mode,
+                    file.inode.map(|v| v as i64),
+                    &file.kind,
+                    &file.archive,
+                    file.offset.map(|v| v as i64),
+                ),
+            )?;
+        }
+
+        for run in &backup.runs {
+            let recorded: bool = tx.query_row(
+                "SELECT EXISTS (SELECT 1 FROM Runs WHERE backup_id = ?1 AND started = ?2 AND host = ?3)",
+                (id as i64, run.started, &run.host),
+                |row| row.get(0),
+            )?;
+            if recorded {
+                continue;
+            }
+            tx.execute(
+                "INSERT INTO Runs (backup_id, started, finished, duration_ms, bytes, file_count,
+                copied_count, error_count, errors, host)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                (
+                    id as i64,
+                    run.started,
+                    run.finished,
+                    run.duration_ms.map(|v| v as i64),
+                    run.bytes as i64,
+                    run.file_count as i64,
+                    run.copied_count as i64,
+                    run.error_count as i64,
+                    run.errors.join("\n"),
+                    &run.host,
+                ),
+            )?;
+            summary.runs += 1;
+        }
+    }
+
+    tx.commit()?;
+    Ok(summary)
+}
@@ -1,31 +1,73 @@
-use crate::{BackupEntry, FileEntry, _copy, _pb_update};
+use crate::archive;
+use crate::catalog;
+use crate::journal;
+use crate::manifest;
+use crate::metadata::FileMeta;
+use crate::pool;
+use crate::remote;
+use crate::runs;
+use crate::s3::S3Path;
+use crate::storage;
+use crate::{
+    _backup_id, _copy, _copy_sources, _discover, _format_duration, _hash_file, _load_backup,
+    _load_files, _load_sources, _pb_update, _relative, BackupEntry, CopyOptions, Discovered,
+    FileEntry, FileSize, TEMP_SUFFIX,
+};
 use colored::Colorize;
 use indicatif::{HumanCount, MultiProgress, ProgressBar, ProgressStyle};
 use indicatif_log_bridge::LogWrapper;
 use log::{error, info};
-use rusqlite::{Result, Transaction};
-use sha2::{Digest, Sha256};
+use rusqlite::{Connection, Result};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::fs::{self, File};
-use std::io::Read;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
-pub fn verify(conn: &Transaction, id: u64) {
+pub fn verify(conn: &Connection, id: u64, jobs: usize) {
+    let Some(backup) = _load_backup(conn, id).unwrap() else {
+        eprintln!("Couldn't find {id}");
+        return;
+    };
+    if archive::is_archive(backup.compression.as_deref()) {
+        let (verified, errors) = archive::verify(conn, id, &backup.to);
+        println!(
+            "{} {} out of {} files. ({} errors occured)",
+            "Verified".green().bold(),
+            HumanCount(verified as u64),
+            HumanCount((verified + errors.len()) as u64),
+            HumanCount(errors.len() as u64),
+        );
+        return;
+    }
+    if let Some(command) = &backup.remote {
+        let mut peer = match remote::Peer::spawn(command) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("{} {}", "Error:".red().bold(), e);
+                return;
+            }
+        };
+        let multi = MultiProgress::new();
+        let logger = colog::default_builder().build();
+        let _ = LogWrapper::new(multi.clone(), logger).try_init();
+        let (verified, errors) = remote::verify(conn, &backup, &mut peer);
+        println!(
+            "{} {} out of {} files. ({} errors occured)",
+            "Verified".green().bold(),
+            HumanCount(verified as u64),
+            HumanCount((verified + errors.len()) as u64),
+            HumanCount(errors.len() as u64),
+        );
+        return;
+    }
+    let Some(backend) = storage::open(&backup) else {
+        return;
+    };
     let mut error_list = Vec::new();
     let mut verified = 0;
-    let mut real_count = 0;
     let mut copied = 0;
-    let mut stmt = conn
-        .prepare("SELECT source, dest, sha256 FROM Files WHERE backup_id = ?1")
-        .unwrap();
-    let iter = stmt
-        .query_map([id as i64], |row| {
-            Ok(FileEntry {
-                backup_id: id,
-                from: row.get::<usize, String>(0).unwrap().into(),
-                to: row.get::<usize, String>(1).unwrap().into(),
-                sha256: row.get_unwrap(2),
-            })
-        })
-        .unwrap();
+    let entries = _load_files(conn, id).unwrap();
     let multi = MultiProgress::new();
     let logger = colog::default_builder().build();
     LogWrapper::new(multi.clone(), logger).try_init().unwrap();
@@ -48,64 +90,24 @@ pub fn verify(conn: &Transaction, id: u64) {
     let pb_clone = pb.clone();
     let t = _pb_update(pb_clone);
 
-    for entry in iter {
-        real_count += 1;
-        let entry = entry.unwrap();
-        info!(
-            "{} \"{}\"",
-            "Verifying".green().bold(),
-            entry.to.display().to_string()
-        );
-        let mut read_from = match File::open(&entry.to) {
-            Ok(v) => v,
-            Err(_) => {
-                info!(
-                    "\n{} {}",
-                    "Copying".blue().bold(),
-                    entry.from.display().to_string()
-                );
-                match fs::copy(&entry.from, &entry.to) {
-                    Ok(v) => v,
-                    Err(e) => {
-                        error!("{e}");
-                        error_list.push(e);
-                        continue;
-                    }
-                };
-                copied += 1;
-                File::open(&entry.to).unwrap()
-            }
-        };
-        let mut hasher = Sha256::new();
-
-        let file_size = read_from.metadata().unwrap().len();
-        let max_buf_size = 1024 * 1024 * 1024 * 4;
-        let buf_size = file_size.min(max_buf_size);
-        let mut buf = Vec::with_capacity(buf_size as usize);
-        while read_from.read_to_end(&mut buf).unwrap() > 0 {
-            hasher.update(&buf);
-        }
-
-        let hash = format!("{:x}", hasher.finalize());
-        if hash != entry.sha256 {
-            info!(
-                "\n{} \"{}\"",
-                "Copying".green().bold(),
-                entry.to.display().to_string()
-            );
-            match fs::copy(entry.from, entry.to) {
-                Ok(v) => v,
+    pool::ordered(
+        jobs,
+        &entries,
+        |entry| storage::verify_entry(backend.as_ref(), entry).map(u64::from),
+        |_, result| {
+            match result {
+                Ok(v) => {
+                    copied += v;
+                    verified += 1;
+                }
                 Err(e) => {
                     error!("{e}");
                     error_list.push(e);
-                    continue;
                 }
-            };
-            copied += 1;
-        }
-        verified += 1;
-        pb.inc(1);
-    }
+            }
+            pb.inc(1);
+        },
+    );
     pb.finish();
     t.join().unwrap();
     multi.remove(&pb);
@@ -113,20 +115,47 @@ pub fn verify(conn: &Transaction, id: u64) {
     println!(
         "{} {} out of {} files. Copied {} files. ({} errors occured)",
         "Verified".green().bold(),
-        HumanCount(verified).to_string(),
-        HumanCount(real_count).to_string(),
-        HumanCount(copied).to_string(),
-        HumanCount(error_list.len() as u64).to_string(),
+        HumanCount(verified),
+        HumanCount(entries.len() as u64),
+        HumanCount(copied),
+        HumanCount(error_list.len() as u64),
     );
 }
 
-fn _count_matches(conn: &Transaction, id: i64) -> Result<usize> {
+fn _count_matches(conn: &Connection, id: i64) -> Result<usize> {
     let mut stmt = conn.prepare("SELECT COUNT(*) FROM Files WHERE backup_id = ?1")?;
     let count: i64 = stmt.query_row([id], |row| row.get(0))?;
     Ok(count as usize)
 }
 
-pub fn revert(conn: &Transaction, id: u64, multithread: bool) {
+pub fn revert(conn: &Connection, id: u64, opts: &CopyOptions) {
+    if let Some(backup) = _load_backup(conn, id).unwrap() {
+        if archive::is_archive(backup.compression.as_deref()) {
+            archive::restore(conn, &backup, opts);
+            return;
+        }
+        if S3Path::parse(&backup.to).is_some() {
+            if let Some(backend) = storage::open(&backup) {
+                storage::restore(conn, backend.as_ref(), &backup);
+            }
+            return;
+        }
+        if backup.remote.is_some() {
+            eprintln!(
+                "{} {id} was sent to a remote peer and can't be reverted from here",
+                "Error:".red().bold()
+            );
+            return;
+        }
+        // Copying the destination back only works for a single source directory, so every
+        // file is restored to the path it was copied from instead.
+        let source_name = backup.from.iter().next_back().unwrap_or_default();
+        if _load_sources(conn, &backup).unwrap().len() > 1 || backup.to.join(source_name).is_file()
+        {
+            storage::restore(conn, &storage::Local::default(), &backup);
+            return;
+        }
+    }
     let mut stmt = conn
         .prepare("SELECT source, dest FROM Backups WHERE id = ?1")
         .unwrap();
@@ -152,42 +181,98 @@ pub fn revert(conn: &Transaction, id: u64, multithread: bool) {
     drop(iter);
     drop(stmt);
 
-    _copy(conn, multithread, source_str.into(), dest_str.into());
+    // The manifest describes a backup, it doesn't belong next to the restored source.
+    let opts = CopyOptions {
+        no_manifest: true,
+        ..opts.clone()
+    };
+    _copy(conn, &opts, source_str.into(), dest_str.into());
 }
 
-pub fn delete(conn: &Transaction, id: u64) {
-    let mut stmt = conn
-        .prepare("SELECT dest FROM Backups WHERE id = ?1")
-        .unwrap();
-    let mut iter = stmt
-        .query_map([id as i64], |row| Ok(row.get(0).unwrap()))
-        .unwrap();
-
-    let dest_str: String;
-    match iter.next() {
-        Some(v) => {
-            let v = v.unwrap();
-            dest_str = v;
+pub fn resume(conn: &Connection, id: Option<u64>, opts: &CopyOptions) {
+    let ids = match id {
+        Some(id) => {
+            if !journal::is_interrupted(conn, id).unwrap() {
+                eprintln!("{id} has no interrupted run to resume");
+                return;
+            }
+            vec![id]
         }
-        None => {
+        None => journal::interrupted(conn).unwrap(),
+    };
+
+    if ids.is_empty() {
+        println!("Nothing to resume");
+        return;
+    }
+
+    for id in ids {
+        let Some(backup) = _load_backup(conn, id).unwrap() else {
             eprintln!("Couldn't find {id}");
-            return;
-        }
+            continue;
+        };
+        _copy_sources(
+            conn,
+            opts,
+            &_load_sources(conn, &backup).unwrap(),
+            backup.to,
+        );
     }
-    drop(iter);
-    drop(stmt);
+}
 
-    match fs::remove_dir_all(&dest_str) {
-        Ok(_) => {}
-        Err(e) => {
-            eprintln!("{} {}", "Error:".red().bold(), e);
-        }
+pub fn delete(conn: &Connection, id: u64) {
+    let Some(backup) = _load_backup(conn, id).unwrap() else {
+        eprintln!("Couldn't find {id}");
+        return;
+    };
+    if let Some(command) = &backup.remote {
+        println!(
+            "{} The files of {id} are on the peer reached with \"{command}\" and weren't deleted.",
+            "[INFO]".bright_yellow()
+        );
+        _delete_entry(conn, id);
+        return;
+    }
+    let Some(backend) = storage::open(&backup) else {
+        return;
     };
-    println!("Deleted {}", dest_str);
+
+    let errors = storage::delete(conn, backend.as_ref(), &backup);
+    for e in &errors {
+        eprintln!("{} {}", "Error:".red().bold(), e);
+    }
+    // Keep the entry so the remaining files can still be found.
+    if !errors.is_empty() {
+        return;
+    }
+    // Manifests are only written next to local backups.
+    let manifest = manifest::path(&backup);
+    if S3Path::parse(&backup.to).is_none() && manifest.is_file() {
+        if let Err(e) = fs::remove_file(&manifest) {
+            eprintln!(
+                "{} {} (\"{}\")",
+                "Error:".red().bold(),
+                e,
+                manifest.display()
+            );
+        }
+        backend.remove_empty_dirs(&backup.to, &backup.to);
+    }
+    println!("Deleted {}", backup.to.display());
+    if let Ok(left) = backend.list(&backup.to) {
+        if !left.is_empty() {
+            println!(
+                "{} {} files in {} weren't part of the backup and were left in place.",
+                "[INFO]".bright_yellow(),
+                HumanCount(left.len() as u64),
+                backup.to.display()
+            );
+        }
+    }
     _delete_entry(conn, id);
 }
 
-pub fn soft_delete(conn: &Transaction, id: u64) {
+pub fn soft_delete(conn: &Connection, id: u64) {
     if _delete_entry(conn, id) {
         println!("Deleted {}", id);
         return;
@@ -195,9 +280,9 @@ pub fn soft_delete(conn: &Transaction, id: u64) {
     eprintln!("Couldn't find \"{}\".", id);
 }
 
-pub fn list(conn: &Transaction) {
+pub fn list(conn: &Connection) {
     let mut stmt = conn
-        .prepare("SELECT id, source, dest, compression FROM Backups")
+        .prepare("SELECT id, source, dest, compression, remote FROM Backups")
         .unwrap();
     let iter = stmt
         .query_map((), |row| {
@@ -206,6 +291,7 @@ pub fn list(conn: &Transaction) {
                 from: row.get::<usize, String>(1).unwrap().into(),
                 to: row.get::<usize, String>(2).unwrap().into(),
                 compression: row.get(3).unwrap_or(None),
+                remote: row.get(4).unwrap_or(None),
             })
         })
         .unwrap();
@@ -213,24 +299,846 @@ pub fn list(conn: &Transaction) {
     for entry in iter {
         let entry = entry.unwrap();
 
-        println!(
-            "{}: {}\n    {}: {}\n    {}: {}",
-            "ID".bold(),
-            entry.id,
-            "Source".bold(),
-            entry.from.display().to_string(),
-            "Destination".bold(),
-            entry.to.display().to_string()
+        println!("{}: {}", "ID".bold(), entry.id);
+        for source in _load_sources(conn, &entry).unwrap() {
+            println!("    {}: {}", "Source".bold(), source.display());
+        }
+        println!("    {}: {}", "Destination".bold(), entry.to.display());
+        if let Some(compression) = entry.compression {
+            println!("    {}: {}", "Compression".bold(), compression);
+        }
+        if let Some(remote) = entry.remote {
+            println!("    {}: {}", "Remote".bold(), remote);
+        }
+    }
+}
+
+pub fn show(
+    conn: &Connection,
+    id: u64,
+    tree: bool,
+    prefix: Option<PathBuf>,
+    glob: Option<String>,
+    hashes: bool,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let backup = match _load_backup(conn, id).unwrap() {
+        Some(v) => v,
+        None => {
+            eprintln!("Couldn't find {id}");
+            return Ok(());
+        }
+    };
+    let pattern = match glob.as_deref().map(glob::Pattern::new).transpose() {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{} Invalid glob pattern: {}", "Error:".red().bold(), e);
+            return Ok(());
+        }
+    };
+
+    let mut files = Vec::new();
+    for entry in _load_files(conn, id).unwrap() {
+        let path = _relative(&entry.to, &backup.to);
+        if prefix.as_ref().is_some_and(|p| !path.starts_with(p)) {
+            continue;
+        }
+        if pattern.as_ref().is_some_and(|p| !p.matches_path(&path)) {
+            continue;
+        }
+        files.push((path, entry));
+    }
+
+    let total: u64 = files.iter().filter_map(|(_, e)| e.size).sum();
+
+    if tree {
+        let mut root = TreeNode::default();
+        for (path, entry) in &files {
+            root.insert(path.iter().map(|c| c.to_owned()).collect(), entry);
+        }
+        writeln!(out, "{}", backup.to.display().to_string().bold())?;
+        root.print(out, "", hashes)?;
+    } else {
+        for (path, entry) in &files {
+            let size = entry
+                .size
+                .map(|v| FileSize::from(v).to_string())
+                .unwrap_or_else(|| "?".to_string());
+            if hashes {
+                writeln!(out, "{}  {:>10}  {}", entry.sha256, size, path.display())?;
+            } else {
+                writeln!(out, "{:>10}  {}", size, path.display())?;
+            }
+        }
+    }
+
+    writeln!(
+        out,
+        "\n{} files ({})",
+        HumanCount(files.len() as u64),
+        FileSize::from(total)
+    )
+}
+
+/// How `find` matches files against its pattern.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FindMode {
+    /// Hashes if the pattern looks like one, globs if it has wildcards and names otherwise
+    Auto,
+    /// File names containing the pattern, ignoring case
+    Name,
+    /// Glob pattern matched against the file name, or the whole path if it contains a `/`
+    Glob,
+    /// SHA-256 hashes starting with the pattern
+    Hash,
+}
+
+/// A file tracked by a backup that matched `find`.
+#[derive(Debug)]
+pub struct Found {
+    pub backup_id: u64,
+    /// Path relative to the destination of the backup.
+    pub path: PathBuf,
+    pub size: Option<u64>,
+    pub sha256: String,
+}
+
+pub fn find(conn: &Connection, pattern: &str, mode: FindMode, hashes: bool) {
+    let found = match find_files(conn, pattern, mode) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{} {}", "Error:".red().bold(), e);
+            return;
+        }
+    };
+
+    for file in &found {
+        let size = file
+            .size
+            .map(|v| FileSize::from(v).to_string())
+            .unwrap_or_else(|| "?".to_string());
+        if hashes {
+            println!(
+                "{}  {}  {:>10}  {}",
+                file.backup_id.to_string().bold(),
+                file.sha256,
+                size,
+                file.path.display()
+            );
+        } else {
+            println!(
+                "{}  {:>10}  {}",
+                file.backup_id.to_string().bold(),
+                size,
+                file.path.display()
+            );
+        }
+    }
+    println!("\n{} matches", HumanCount(found.len() as u64));
+}
+
+/// Searches the files of every backup for `pattern`.
+pub fn find_files(
+    conn: &Connection,
+    pattern: &str,
+    mode: FindMode,
+) -> std::result::Result<Vec<Found>, String> {
+    let mode = match mode {
+        FindMode::Auto if pattern.len() >= 8 && pattern.chars().all(|c| c.is_ascii_hexdigit()) => {
+            FindMode::Hash
+        }
+        FindMode::Auto if pattern.contains(['*', '?', '[']) => FindMode::Glob,
+        FindMode::Auto => FindMode::Name,
+        v => v,
+    };
+    let glob = match mode {
+        FindMode::Glob => Some(glob::Pattern::new(pattern).map_err(|e| e.to_string())?),
+        _ => None,
+    };
+    let name = pattern.to_lowercase();
+    let hash = format!("{}%", pattern.to_lowercase());
+
+    let mut stmt = conn
+        .prepare(
+            "SELECT f.backup_id, f.dest, f.size, f.sha256, b.dest FROM Files f
+            JOIN Backups b ON b.id = f.backup_id
+            WHERE ?1 IS NULL OR f.sha256 LIKE ?1
+            ORDER BY f.backup_id, f.dest",
+        )
+        .map_err(|e| e.to_string())?;
+    let rows = stmt
+        .query_map([(mode == FindMode::Hash).then_some(hash)], |row| {
+            let dest: PathBuf = row.get::<usize, String>(1)?.into();
+            let root: PathBuf = row.get::<usize, String>(4)?.into();
+            Ok(Found {
+                backup_id: row.get::<usize, i64>(0)? as u64,
+                path: _relative(&dest, &root),
+                size: row.get::<usize, Option<i64>>(2)?.map(|v| v as u64),
+                sha256: row.get(3)?,
+            })
+        })
+        .map_err(|e| e.to_string())?;
+
+    let mut found = Vec::new();
+    for file in rows {
+        let file = file.map_err(|e| e.to_string())?;
+        let file_name = file.path.file_name().unwrap_or_default().to_string_lossy();
+        let matches = match mode {
+            FindMode::Glob if pattern.contains('/') => {
+                glob.as_ref().unwrap().matches_path(&file.path)
+            }
+            FindMode::Glob => glob.as_ref().unwrap().matches(&file_name),
+            FindMode::Name => file_name.to_lowercase().contains(&name),
+            _ => true,
+        };
+        if matches {
+            found.push(file);
+        }
+    }
+    Ok(found)
+}
+
+/// How a file changed since it was backed up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Change {
+    Added,
+    Removed,
+    Modified,
+    Unchanged,
+}
+
+pub fn diff(conn: &Connection, id: u64, quick: bool, all: bool) {
+    let backup = match _load_backup(conn, id).unwrap() {
+        Some(v) => v,
+        None => {
+            eprintln!("Couldn't find {id}");
+            return;
+        }
+    };
+
+    let changes = match diff_source(conn, &backup, quick) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{} {}", "Error:".red().bold(), e);
+            return;
+        }
+    };
+
+    // Paths of backups with several sources keep the name of their source.
+    let sources = _load_sources(conn, &backup).unwrap();
+    let root = |path: &Path| match sources.as_slice() {
+        [source] if path != source => source.clone(),
+        _ => sources
+            .iter()
+            .find(|v| path.starts_with(v))
+            .and_then(|v| v.parent())
+            .unwrap_or(Path::new(""))
+            .to_path_buf(),
+    };
+    let changes: Vec<_> = changes
+        .into_iter()
+        .map(|(path, change)| (_relative(&path, &root(&path)), change))
+        .collect();
+    _print_changes(&changes, all);
+}
+
+pub fn compare(conn: &Connection, a: u64, b: u64, all: bool) {
+    for id in [a, b] {
+        if _load_backup(conn, id).unwrap().is_none() {
+            eprintln!("Couldn't find {id}");
+            return;
+        }
+    }
+    _print_changes(&compare_backups(conn, a, b).unwrap(), all);
+}
+
+/// Compares the files tracked by two backups by their paths relative to the destination,
+/// using only the hashes in the catalog. Changes are from `a` to `b`, so files only in `b`
+/// are [`Change::Added`]. Returns the relative path of every file, sorted by path.
+pub fn compare_backups(conn: &Connection, a: u64, b: u64) -> Result<Vec<(PathBuf, Change)>> {
+    let relative = |id| -> Result<BTreeMap<PathBuf, FileEntry>> {
+        let root = _load_backup(conn, id)?.map(|v| v.to).unwrap_or_default();
+        Ok(_load_files(conn, id)?
+            .into_iter()
+            .map(|v| (_relative(&v.to, &root), v))
+            .collect())
+    };
+    let a = relative(a)?;
+    let mut b = relative(b)?;
+
+    let mut changes = Vec::with_capacity(a.len().max(b.len()));
+    for (path, entry) in a {
+        let change = match b.remove(&path) {
+            None => Change::Removed,
+            Some(other)
+                if other.size.is_some() && entry.size.is_some() && other.size != entry.size =>
+            {
+                Change::Modified
+            }
+            Some(other) if other.sha256 != entry.sha256 => Change::Modified,
+            Some(_) => Change::Unchanged,
+        };
+        changes.push((path, change));
+    }
+    changes.extend(b.into_keys().map(|v| (v, Change::Added)));
+    changes.sort();
+    Ok(changes)
+}
+
+/// Prints `changes` as a list and a summary. Unchanged files are only listed if `all` is set.
+fn _print_changes(changes: &[(PathBuf, Change)], all: bool) {
+    let mut counts = [0u64; 4];
+    for (path, change) in changes {
+        counts[*change as usize] += 1;
+        match change {
+            Change::Added => println!("{} {}", "+".green().bold(), path.display()),
+            Change::Removed => println!("{} {}", "-".red().bold(), path.display()),
+            Change::Modified => println!("{} {}", "~".yellow().bold(), path.display()),
+            Change::Unchanged if all => println!("  {}", path.display()),
+            Change::Unchanged => {}
+        }
+    }
+
+    println!(
+        "\n{} added, {} removed, {} modified, {} unchanged",
+        HumanCount(counts[Change::Added as usize])
+            .to_string()
+            .green(),
+        HumanCount(counts[Change::Removed as usize])
+            .to_string()
+            .red(),
+        HumanCount(counts[Change::Modified as usize])
+            .to_string()
+            .yellow(),
+        HumanCount(counts[Change::Unchanged as usize]),
+    );
+}
+
+/// How a file compares to its entry in a checksum file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CheckStatus {
+    Ok,
+    Mismatched,
+    Missing,
+    /// The file isn't listed in the checksum file.
+    Extra,
+}
+
+/// Verifies `dir` against the checksum file `sums`. Returns `false` if any file is missing,
+/// extra or doesn't match its hash.
+pub fn check(dir: &Path, sums: &Path, all: bool) -> bool {
+    let entries = match fs::read_to_string(sums) {
+        Ok(text) => manifest::parse(&text).map_err(|e| format!("Couldn't parse {e}")),
+        Err(e) => Err(e.to_string()),
+    };
+    let entries = match entries {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{} {} (\"{}\")", "Error:".red().bold(), e, sums.display());
+            return false;
+        }
+    };
+
+    let multi = MultiProgress::new();
+    let logger = colog::default_builder().build();
+    let _ = LogWrapper::new(multi.clone(), logger).try_init();
+
+    let results = match check_sums(dir, &entries, &[sums.to_path_buf()]) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{} {} (\"{}\")", "Error:".red().bold(), e, dir.display());
+            return false;
+        }
+    };
+
+    let mut counts = [0u64; 4];
+    for (path, status) in &results {
+        counts[*status as usize] += 1;
+        match status {
+            CheckStatus::Ok if all => println!("  {}", path.display()),
+            CheckStatus::Ok => {}
+            CheckStatus::Mismatched => println!("{} {}", "!".red().bold(), path.display()),
+            CheckStatus::Missing => println!("{} {}", "-".red().bold(), path.display()),
+            CheckStatus::Extra => println!("{} {}", "+".yellow().bold(), path.display()),
+        }
+    }
+    println!(
+        "\n{} ok, {} mismatched, {} missing, {} extra",
+        HumanCount(counts[CheckStatus::Ok as usize])
+            .to_string()
+            .green(),
+        HumanCount(counts[CheckStatus::Mismatched as usize])
+            .to_string()
+            .red(),
+        HumanCount(counts[CheckStatus::Missing as usize])
+            .to_string()
+            .red(),
+        HumanCount(counts[CheckStatus::Extra as usize])
+            .to_string()
+            .yellow(),
+    );
+    results.iter().all(|(_, v)| *v == CheckStatus::Ok)
+}
+
+/// Hashes the files listed in `entries`, relative to `dir`, and compares them with their
+/// expected hashes. Files in `dir` that aren't listed are reported as extra, except for
+/// temporary files and the paths in `ignore`. Returns the status of every path relative to
+/// `dir`, sorted by path.
+pub fn check_sums(
+    dir: &Path,
+    entries: &[(PathBuf, String)],
+    ignore: &[PathBuf],
+) -> io::Result<Vec<(PathBuf, CheckStatus)>> {
+    let mut extra = BTreeMap::new();
+    _discover(fs::read_dir(dir)?, |discovered| match discovered {
+        Discovered::File(entry, _) => {
+            if !entry.file_name().to_string_lossy().ends_with(TEMP_SUFFIX) {
+                extra.insert(_relative(&entry.path(), dir), ());
+            }
+        }
+        Discovered::FdLimit => {
+            error!("Too many file handles open, some extra files may not be reported.");
+        }
+    });
+    let canonical_dir = fs::canonicalize(dir)?;
+    for path in ignore {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+        extra.remove(&_relative(&path, &canonical_dir));
+    }
+
+    let mut results = Vec::with_capacity(entries.len());
+    for (path, hash) in entries {
+        extra.remove(path);
+        let full = dir.join(path);
+        info!("{} \"{}\"", "Verifying".green().bold(), full.display());
+        let status = match _hash_file(&full) {
+            Ok(v) if v == *hash => CheckStatus::Ok,
+            Ok(_) => CheckStatus::Mismatched,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => CheckStatus::Missing,
+            Err(e) => {
+                error!("Couldn't hash {:#?} because of error: {e}", full);
+                CheckStatus::Mismatched
+            }
+        };
+        results.push((path.clone(), status));
+    }
+    results.extend(extra.into_keys().map(|v| (v, CheckStatus::Extra)));
+    results.sort();
+    Ok(results)
+}
+
+pub fn adopt(conn: &Connection, source: &Path, dest: &Path) {
+    let id = _backup_id(source, dest);
+    conn.execute(
+        "INSERT INTO Backups (id, source, dest) VALUES (?1, ?2, ?3)
+        ON CONFLICT (id) DO UPDATE SET source = excluded.source, dest = excluded.dest",
+        (
+            id as i64,
+            source.display().to_string(),
+            dest.display().to_string(),
+        ),
+    )
+    .unwrap();
+    reindex(conn, id);
+}
+
+pub fn reindex(conn: &Connection, id: u64) {
+    let backup = match _load_backup(conn, id).unwrap() {
+        Some(v) => v,
+        None => {
+            eprintln!("Couldn't find {id}");
+            return;
+        }
+    };
+    if archive::is_archive(backup.compression.as_deref()) {
+        eprintln!(
+            "{} {id} is stored in archives and can't be reindexed",
+            "Error:".red().bold()
         );
+        return;
+    }
+    if backup.remote.is_some() || S3Path::parse(&backup.to).is_some() {
+        eprintln!(
+            "{} {id} isn't stored on this machine and can't be reindexed",
+            "Error:".red().bold()
+        );
+        return;
+    }
+
+    let multi = MultiProgress::new();
+    let logger = colog::default_builder().build();
+    let _ = LogWrapper::new(multi.clone(), logger).try_init();
+
+    match index_dest(conn, &backup) {
+        Ok((indexed, paired)) => println!(
+            "{} {} files of {}. {} of them were found in the source.",
+            "Indexed".green().bold(),
+            HumanCount(indexed as u64),
+            id,
+            HumanCount(paired as u64),
+        ),
+        Err(e) => eprintln!(
+            "{} {} (\"{}\")",
+            "Error:".red().bold(),
+            e,
+            backup.to.display()
+        ),
+    }
+}
+
+/// Replaces the files tracked by `backup` with the files found in its destination tree,
+/// hashing each of them. Every file is paired with the source path it would have been
+/// copied from, and the metadata of that source file is recorded if it still exists with
+/// the same size and modification time.
+/// Returns how many files were indexed and how many of them exist in the source.
+pub fn index_dest(conn: &Connection, backup: &BackupEntry) -> io::Result<(usize, usize)> {
+    let mut found = Vec::new();
+    for source in _load_sources(conn, backup).map_err(io::Error::other)? {
+        let source_name = source.iter().next_back().unwrap_or_default();
+        let root = backup.to.join(source_name);
+        if let Ok(meta) = fs::metadata(&root) {
+            if meta.is_file() {
+                found.push((root, FileMeta::from(&meta), source));
+                continue;
+            }
+        }
+        _discover(fs::read_dir(&root)?, |discovered| match discovered {
+            Discovered::File(entry, meta) => {
+                if !entry.file_name().to_string_lossy().ends_with(TEMP_SUFFIX) {
+                    let from = source.join(_relative(&entry.path(), &root));
+                    found.push((entry.path(), meta, from));
+                }
+            }
+            Discovered::FdLimit => {
+                error!("Too many file handles open, some files won't be indexed.");
+            }
+        });
+    }
+    found.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let tx = conn.unchecked_transaction().unwrap();
+    tx.execute("DELETE FROM Files WHERE backup_id = ?1", [backup.id as i64])
+        .unwrap();
+    let mut paired = 0;
+    let mut indexed = 0;
+    for (path, dest_meta, from) in found {
+        info!("{} \"{}\"", "Hashing".green().bold(), path.display());
+        let hash = match _hash_file(&path) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Couldn't hash {:#?} because of error: {e}. Skipping", path);
+                continue;
+            }
+        };
+        // The source's metadata is only trusted if it looks like the file that was copied,
+        // otherwise `diff --quick` would take a stale copy for an up to date one.
+        let meta = match fs::symlink_metadata(&from) {
+            Ok(v) if v.is_file() => {
+                paired += 1;
+                let source_meta = FileMeta::from(&v);
+                match (source_meta.size, source_meta.mtime) == (dest_meta.size, dest_meta.mtime) {
+                    true => source_meta,
+                    false => dest_meta,
+                }
+            }
+            _ => dest_meta,
+        };
+        tx.execute(
+            "INSERT INTO Files (backup_id, source, dest, sha256, size, mtime, ctime, mode, inode, kind)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            (
+                backup.id as i64,
+                from.display().to_string(),
+                path.display().to_string(),
+                hash,
+                meta.size as i64,
+                meta.mtime,
+                meta.ctime,
+                meta.mode,
+                meta.inode.map(|v| v as i64),
+                meta.kind.as_str(),
+            ),
+        )
+        .unwrap();
+        indexed += 1;
     }
+    tx.commit().unwrap();
+    Ok((indexed, paired))
 }
 
-fn _delete_entry(conn: &Transaction, id: u64) -> bool {
-    match conn
-        .execute("DELETE FROM Backups WHERE id = ?1", [id as i64])
+/// Compares the live source tree of `backup` with the files tracked in the catalog, without
+/// copying anything. Files are compared by hash, or only by size and modification time if
+/// `quick` is set. Returns the source path of every file and how it changed, sorted by path.
+pub fn diff_source(
+    conn: &Connection,
+    backup: &BackupEntry,
+    quick: bool,
+) -> std::io::Result<Vec<(PathBuf, Change)>> {
+    let mut live = BTreeMap::new();
+    for source in _load_sources(conn, backup).map_err(io::Error::other)? {
+        if let Ok(meta) = fs::metadata(&source) {
+            if meta.is_file() {
+                live.insert(source, FileMeta::from(&meta));
+                continue;
+            }
+        }
+        let dir = fs::read_dir(&source)
+            .map_err(|e| io::Error::new(e.kind(), format!("{e} (\"{}\")", source.display())))?;
+        _discover(dir, |discovered| match discovered {
+            Discovered::File(entry, meta) => {
+                live.insert(entry.path(), meta);
+            }
+            Discovered::FdLimit => {
+                error!("Too many file handles open, the diff will be incomplete.");
+            }
+        });
+    }
+
+    let mut tracked: BTreeMap<PathBuf, FileEntry> = _load_files(conn, backup.id)
         .unwrap()
-    {
-        0 => false,
-        _ => true,
+        .into_iter()
+        .map(|v| (v.from.clone(), v))
+        .collect();
+
+    let mut changes = Vec::with_capacity(live.len());
+    for (path, meta) in live {
+        let change = match tracked.remove(&path) {
+            Some(entry) => _compare(&path, &meta, &entry, quick),
+            None => Change::Added,
+        };
+        changes.push((path, change));
+    }
+    changes.extend(tracked.into_keys().map(|v| (v, Change::Removed)));
+    changes.sort();
+    Ok(changes)
+}
+
+fn _compare(path: &Path, meta: &FileMeta, entry: &FileEntry, quick: bool) -> Change {
+    if entry.size.is_some_and(|v| v != meta.size) {
+        return Change::Modified;
+    }
+    if quick && entry.size.is_some() && entry.mtime.is_some() {
+        return match entry.mtime == Some(meta.mtime) {
+            true => Change::Unchanged,
+            false => Change::Modified,
+        };
+    }
+    match _hash_file(path) {
+        Ok(hash) if hash == entry.sha256 => Change::Unchanged,
+        Ok(_) => Change::Modified,
+        Err(e) => {
+            error!("Couldn't hash {:#?} because of error: {e}", path);
+            Change::Modified
+        }
+    }
+}
+
+/// A directory or file in the tree printed by `show --tree`.
+#[derive(Default)]
+struct TreeNode<'a> {
+    children: BTreeMap<OsString, TreeNode<'a>>,
+    file: Option<&'a FileEntry>,
+    size: u64,
+}
+
+impl<'a> TreeNode<'a> {
+    fn insert(&mut self, mut path: Vec<OsString>, entry: &'a FileEntry) {
+        self.size += entry.size.unwrap_or(0);
+        if path.is_empty() {
+            self.file = Some(entry);
+            return;
+        }
+        let name = path.remove(0);
+        self.children.entry(name).or_default().insert(path, entry);
+    }
+
+    fn print(&self, out: &mut impl Write, indent: &str, hashes: bool) -> io::Result<()> {
+        let count = self.children.len();
+        for (i, (name, child)) in self.children.iter().enumerate() {
+            let last = i + 1 == count;
+            let branch = if last { "└── " } else { "├── " };
+            let size = FileSize::from(child.size).to_string();
+            match child.file {
+                Some(entry) if hashes => writeln!(
+                    out,
+                    "{}{}{} ({}) {}",
+                    indent,
+                    branch,
+                    name.to_string_lossy(),
+                    size.truecolor(150, 150, 150),
+                    entry.sha256.truecolor(150, 150, 150)
+                )?,
+                Some(_) => writeln!(
+                    out,
+                    "{}{}{} ({})",
+                    indent,
+                    branch,
+                    name.to_string_lossy(),
+                    size.truecolor(150, 150, 150)
+                )?,
+                None => writeln!(
+                    out,
+                    "{}{}{} ({})",
+                    indent,
+                    branch,
+                    name.to_string_lossy().blue().bold(),
+                    size.truecolor(150, 150, 150)
+                )?,
+            }
+            let indent = format!("{}{}", indent, if last { "    " } else { "│   " });
+            child.print(out, &indent, hashes)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn history(
+    conn: &Connection,
+    id: Option<u64>,
+    limit: usize,
+    show_errors: bool,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let runs = runs::list(conn, id, limit).unwrap();
+    if runs.is_empty() {
+        match id {
+            Some(id) => eprintln!("Couldn't find any runs of {id}"),
+            None => writeln!(out, "No backups were run yet")?,
+        }
+        return Ok(());
+    }
+
+    for run in runs {
+        let started = chrono::DateTime::from_timestamp_millis(run.started)
+            .unwrap_or_default()
+            .with_timezone(&chrono::Local);
+        let status = if run.finished.is_none() {
+            "Interrupted".yellow().bold()
+        } else if run.succeeded() {
+            "Succeeded".green().bold()
+        } else {
+            "Failed".red().bold()
+        };
+
+        writeln!(
+            out,
+            "{}: {} ({})\n    {}: {}\n    {}: {}\n    {}: {}",
+            "Run".bold(),
+            run.id,
+            status,
+            "Backup".bold(),
+            run.backup_id,
+            "Started".bold(),
+            started.format("%Y-%m-%d %H:%M:%S"),
+            "Host".bold(),
+            run.host,
+        )?;
+        if let Some(duration) = run.duration {
+            writeln!(
+                out,
+                "    {}: {}\n    {}: {} of {} files ({})\n    {}: {}",
+                "Duration".bold(),
+                _format_duration(duration),
+                "Copied".bold(),
+                HumanCount(run.copied_count),
+                HumanCount(run.file_count),
+                FileSize::from(run.bytes),
+                "Errors".bold(),
+                HumanCount(run.error_count),
+            )?;
+        }
+        if show_errors {
+            for err in &run.errors {
+                writeln!(out, "        {}", err.red())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn gc(conn: &Connection) {
+    let removed = remove_orphans(conn).unwrap();
+    conn.execute_batch("VACUUM").unwrap();
+    println!(
+        "{} {} catalog entries that didn't belong to any backup",
+        "Removed".green().bold(),
+        HumanCount(removed.values().sum::<usize>() as u64),
+    );
+    for (table, count) in &removed {
+        println!("    {table}: {}", HumanCount(*count as u64));
+    }
+}
+
+/// Deletes the rows whose backup no longer exists, in every table that references `Backups`.
+/// Returns how many rows were deleted, by table.
+pub fn remove_orphans(conn: &Connection) -> Result<BTreeMap<String, usize>> {
+    let orphans = conn
+        .prepare("PRAGMA foreign_key_check")?
+        .query_map((), |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Option<i64>>(1)?))
+        })?
+        .collect::<Result<Vec<_>>>()?;
+    let mut removed = BTreeMap::new();
+    for (table, rowid) in orphans {
+        let Some(rowid) = rowid else { continue };
+        conn.execute(
+            &format!("DELETE FROM \"{table}\" WHERE rowid = ?1"),
+            [rowid],
+        )?;
+        *removed.entry(table).or_insert(0) += 1;
+    }
+    Ok(removed)
+}
+
+fn _delete_entry(conn: &Connection, id: u64) -> bool {
+    conn.execute("DELETE FROM Backups WHERE id = ?1", [id as i64])
+        .unwrap()
+        != 0
+}
+
+pub fn catalog_export(conn: &Connection, output: Option<PathBuf>, ndjson: bool) {
+    let result = match &output {
+        Some(path) => File::create(path)
+            .map_err(catalog::CatalogError::from)
+            .and_then(|f| catalog::export(conn, &mut io::BufWriter::new(f), ndjson)),
+        None => catalog::export(conn, &mut io::stdout().lock(), ndjson),
+    };
+    match (result, output) {
+        (Err(e), _) => eprintln!("{} {}", "Error:".red().bold(), e),
+        (Ok(()), Some(path)) => println!(
+            "{} the catalog to \"{}\"",
+            "Exported".green().bold(),
+            path.display()
+        ),
+        (Ok(()), None) => {}
     }
 }
+
+pub fn catalog_import(conn: &Connection, input: &Path) {
+    let catalog = match File::open(input)
+        .map_err(catalog::CatalogError::from)
+        .and_then(|f| catalog::parse(io::BufReader::new(f)))
+    {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{} {}", "Error:".red().bold(), e);
+            return;
+        }
+    };
+    let summary = match catalog::import(conn, &catalog) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{} {}", "Error:".red().bold(), e);
+            return;
+        }
+    };
+
+    println!(
+        "{} {} new backups, merged {} existing ones. Added {} files and {} runs.",
+        "Imported".green().bold(),
+        HumanCount(summary.backups_added as u64),
+        HumanCount(summary.backups_merged as u64),
+        HumanCount(summary.files as u64),
+        HumanCount(summary.runs as u64),
+    );
+}
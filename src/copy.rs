@@ -0,0 +1,145 @@
+use crate::ratelimit::{self, Throttled};
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+/// Whether files are cloned instead of copied on filesystems that support it, such as
+/// btrfs and XFS. A clone shares its data with the original until either is modified.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Reflink {
+    /// Always copies the data
+    #[default]
+    Never,
+    /// Clones files when possible and copies them otherwise
+    Auto,
+    /// Fails if a file can't be cloned
+    Always,
+}
+
+/// How a file was copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyMethod {
+    /// Cloned with `FICLONE`, no data was copied.
+    Reflink,
+    /// Copied by the kernel with `copy_file_range`.
+    CopyFileRange,
+    /// Read and written through userspace buffers.
+    Buffered,
+}
+
+impl fmt::Display for CopyMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CopyMethod::Reflink => "reflink",
+            CopyMethod::CopyFileRange => "copy_file_range",
+            CopyMethod::Buffered => "buffered",
+        })
+    }
+}
+
+/// Copies the contents and permissions of `from` to `to`, creating or truncating it. Uses
+/// the fastest method available, unless a bandwidth limit is set in which case the data is
+/// always read through the limiter. Returns the number of bytes copied and the method used.
+pub fn copy(from: &Path, to: &Path, reflink: Reflink) -> io::Result<(u64, CopyMethod)> {
+    let mut reader = File::open(from)?;
+    let meta = reader.metadata()?;
+    let mut writer = File::create(to)?;
+
+    let mut method = None;
+    if reflink != Reflink::Never {
+        match _reflink(&reader, &writer) {
+            Ok(()) => method = Some((meta.len(), CopyMethod::Reflink)),
+            Err(e) if reflink == Reflink::Always => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("Couldn't clone {:#?}: {e}", from),
+                ))
+            }
+            Err(_) => {}
+        }
+    }
+    if method.is_none() && !ratelimit::is_limited() {
+        method =
+            _copy_file_range(&reader, &writer, meta.len())?.map(|n| (n, CopyMethod::CopyFileRange));
+    }
+    let (n, method) = match method {
+        Some(v) => v,
+        None => (
+            io::copy(&mut Throttled(&mut reader), &mut writer)?,
+            CopyMethod::Buffered,
+        ),
+    };
+
+    fs::set_permissions(to, meta.permissions())?;
+    Ok((n, method))
+}
+
+#[cfg(target_os = "linux")]
+fn _reflink(reader: &File, writer: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: Both descriptors are open for the duration of the call.
+    match unsafe { libc::ioctl(writer.as_raw_fd(), libc::FICLONE, reader.as_raw_fd()) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn _reflink(_reader: &File, _writer: &File) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflinks aren't supported on this platform",
+    ))
+}
+
+/// Copies `len` bytes with `copy_file_range`. Returns `None` without copying anything if
+/// it's not supported for these files, e.g. across filesystems on older kernels.
+#[cfg(target_os = "linux")]
+fn _copy_file_range(reader: &File, writer: &File, len: u64) -> io::Result<Option<u64>> {
+    use std::os::fd::AsRawFd;
+
+    let mut copied = 0u64;
+    loop {
+        let chunk = (len.saturating_sub(copied)).clamp(1, 1 << 30) as usize;
+        // SAFETY: Both descriptors are open for the duration of the call and null offsets
+        // make the kernel use and advance the file positions.
+        let n = unsafe {
+            libc::copy_file_range(
+                reader.as_raw_fd(),
+                std::ptr::null_mut(),
+                writer.as_raw_fd(),
+                std::ptr::null_mut(),
+                chunk,
+                0,
+            )
+        };
+        match n {
+            -1 => {
+                let e = io::Error::last_os_error();
+                let unsupported = matches!(
+                    e.raw_os_error(),
+                    Some(
+                        libc::ENOSYS | libc::EXDEV | libc::EINVAL | libc::EOPNOTSUPP | libc::EPERM
+                    )
+                );
+                return match unsupported && copied == 0 {
+                    true => Ok(None),
+                    false => Err(e),
+                };
+            }
+            // Some filesystems report nothing to copy instead of failing, let the buffered
+            // copy find out whether the file is really empty.
+            0 if copied == 0 && len > 0 => return Ok(None),
+            // The file may have grown since its size was read, so copy until the end.
+            0 => return Ok(Some(copied)),
+            n => copied += n as u64,
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn _copy_file_range(_reader: &File, _writer: &File, _len: u64) -> io::Result<Option<u64>> {
+    Ok(None)
+}
@@ -0,0 +1,371 @@
+//! Block-level delta updates of large files that already exist in the destination.
+//!
+//! Works like rsync: the destination is split into fixed size blocks, each with a weak
+//! rolling checksum and a strong SHA-256 hash. The source is scanned with a window the size
+//! of a block whose weak checksum is rolled forward one byte at a time, and when it matches a
+//! block of the destination the strong hash confirms it. Matching blocks are reused from the
+//! destination, everything else is written from the source.
+//!
+//! The new contents are written to a temporary copy of the destination which is renamed over
+//! it once they're complete, so an interrupted update leaves the destination as it was and
+//! the next run removes the copy. On filesystems that support reflinks the copy is a clone, so
+//! blocks that didn't change aren't written at all, which is how VM images and databases
+//! change. Elsewhere the kernel copies the destination first. Since the destination itself
+//! isn't touched, its blocks are reused wherever they moved.
+//!
+//! The checksums of every block are kept in the `Blocks` table along with the size and
+//! modification time of the destination once it was verified. As long as the destination
+//! still has that size and modification time, the checksums are trusted and the destination
+//! is only read for blocks that moved. Otherwise, they're computed from the destination first.
+
+use crate::copy::{self, Reflink};
+use crate::metadata::mtime_ns;
+use crate::ratelimit::Throttled;
+use crate::storage::Local;
+use rusqlite::Connection;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Size of the blocks files are compared in.
+pub const BLOCK_SIZE: usize = 128 * 1024;
+
+/// Files smaller than this are always copied whole.
+pub const MIN_SIZE: u64 = 4 * BLOCK_SIZE as u64;
+
+type BlockHash = [u8; 32];
+
+/// The checksums of one block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockSum {
+    weak: u64,
+    strong: BlockHash,
+}
+
+impl BlockSum {
+    fn of(data: &[u8]) -> Self {
+        Self {
+            weak: Rolling::new(data).digest(),
+            strong: Sha256::digest(data).into(),
+        }
+    }
+}
+
+/// rsync's weak checksum of a window of bytes. It can be moved forward by a byte without
+/// reading the rest of the window again. Both of its sums are kept in full rather than
+/// truncated to 16 bits, which makes them collide all the time on repetitive data.
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(data: &[u8]) -> Self {
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, &v) in data.iter().enumerate() {
+            a = a.wrapping_add(v as u32);
+            b = b.wrapping_add(((data.len() - i) as u32).wrapping_mul(v as u32));
+        }
+        Self {
+            a,
+            b,
+            len: data.len() as u32,
+        }
+    }
+
+    /// Drops `out` from the start of the window and adds `next` at its end, or shrinks the
+    /// window if the data ended.
+    fn roll(&mut self, out: u8, next: Option<u8>) {
+        self.a = self.a.wrapping_sub(out as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32));
+        match next {
+            Some(v) => {
+                self.a = self.a.wrapping_add(v as u32);
+                self.b = self.b.wrapping_add(self.a);
+            }
+            None => self.len -= 1,
+        }
+    }
+
+    fn digest(&self) -> u64 {
+        self.a as u64 | (self.b as u64) << 32
+    }
+}
+
+/// The block checksums of a destination file recorded by an earlier run.
+#[derive(Debug)]
+pub struct Signature {
+    block_size: usize,
+    blocks: Vec<BlockSum>,
+    /// Size and modification time of the destination when it was last verified, unset if it
+    /// never was.
+    dest_size: Option<u64>,
+    dest_mtime: Option<i64>,
+}
+
+/// The signatures of every file of a backup, by source path.
+#[derive(Debug, Default)]
+pub struct Signatures {
+    entries: HashMap<PathBuf, Signature>,
+}
+
+impl Signatures {
+    /// Loads the signatures of `backup_id`.
+    pub fn load(conn: &Connection, backup_id: u64) -> rusqlite::Result<Self> {
+        let mut stmt = conn.prepare(
+            "SELECT source, block_size, hashes, weak, dest_size, dest_mtime FROM Blocks
+            WHERE backup_id = ?1",
+        )?;
+        let entries = stmt
+            .query_map([backup_id as i64], |row| {
+                let hashes: Vec<u8> = row.get(2)?;
+                let weak: Vec<u8> = row.get(3)?;
+                Ok((
+                    PathBuf::from(row.get::<usize, String>(0)?),
+                    Signature {
+                        block_size: row.get::<usize, i64>(1)? as usize,
+                        blocks: hashes
+                            .chunks_exact(32)
+                            .zip(weak.chunks_exact(8))
+                            .map(|(strong, weak)| BlockSum {
+                                weak: u64::from_le_bytes(weak.try_into().unwrap()),
+                                strong: strong.try_into().unwrap(),
+                            })
+                            .collect(),
+                        dest_size: row.get::<usize, Option<i64>>(4)?.map(|v| v as u64),
+                        dest_mtime: row.get(5)?,
+                    },
+                ))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(Self { entries })
+    }
+
+    pub fn get(&self, source: &Path) -> Option<&Signature> {
+        self.entries.get(source)
+    }
+}
+
+/// Flattens block checksums into the `hashes` and `weak` blobs stored in the `Blocks` table.
+pub fn to_blobs(blocks: &[BlockSum]) -> (Vec<u8>, Vec<u8>) {
+    (
+        blocks.iter().flat_map(|v| v.strong).collect(),
+        blocks.iter().flat_map(|v| v.weak.to_le_bytes()).collect(),
+    )
+}
+
+fn _read_block(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..])? {
+            0 => break,
+            v => n += v,
+        }
+    }
+    Ok(n)
+}
+
+/// Calls `on_block` with every block read from `reader`.
+fn _for_each_block(reader: &mut impl Read, mut on_block: impl FnMut(&[u8])) -> io::Result<()> {
+    let mut buf = vec![0; BLOCK_SIZE];
+    loop {
+        let n = _read_block(reader, &mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        on_block(&buf[..n]);
+    }
+}
+
+/// Returns the hex encoded SHA-256 hash of the file at `path` along with the checksums of
+/// each of its blocks, reading it once.
+pub fn hash_blocks(path: &Path) -> io::Result<(String, Vec<BlockSum>)> {
+    let mut hasher = Sha256::new();
+    let mut blocks = Vec::new();
+    _for_each_block(&mut Throttled(File::open(path)?), |data| {
+        hasher.update(data);
+        blocks.push(BlockSum::of(data));
+    })?;
+    Ok((format!("{:x}", hasher.finalize()), blocks))
+}
+
+/// Writes the new contents of the destination from its start into a copy of it.
+struct Output {
+    file: File,
+    /// The destination as it was before the update.
+    old: File,
+    offset: u64,
+    written: u64,
+    buf: Vec<u8>,
+}
+
+impl Output {
+    /// Writes data from the source at the current offset.
+    fn literal(&mut self, data: &[u8]) -> io::Result<()> {
+        if !data.is_empty() {
+            self.file.seek(SeekFrom::Start(self.offset))?;
+            self.file.write_all(data)?;
+            self.offset += data.len() as u64;
+            self.written += data.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Reuses the `len` bytes of the old destination at `from` at the current offset. Blocks
+    /// that are already there aren't touched.
+    fn reuse(&mut self, from: u64, len: usize) -> io::Result<()> {
+        if from != self.offset {
+            self.old.seek(SeekFrom::Start(from))?;
+            Throttled(&mut self.old).read_exact(&mut self.buf[..len])?;
+            self.file.seek(SeekFrom::Start(self.offset))?;
+            self.file.write_all(&self.buf[..len])?;
+            self.written += len as u64;
+        }
+        self.offset += len as u64;
+        Ok(())
+    }
+}
+
+/// Replaces `to` with the contents of `from`, reusing the blocks of `to` found anywhere in
+/// `from` and writing the rest. `signature` is used instead of reading `to` if it still
+/// describes it. Returns the number of bytes written.
+pub fn update(
+    from: &Path,
+    to: &Path,
+    signature: Option<&Signature>,
+    local: &Local,
+) -> io::Result<u64> {
+    let mut reader = Throttled(File::open(from)?);
+    let mut old = File::open(to)?;
+    let old_meta = old.metadata()?;
+    let signature = signature.filter(|v| {
+        v.block_size == BLOCK_SIZE
+            && v.dest_size == Some(old_meta.len())
+            && v.dest_mtime == Some(mtime_ns(&old_meta))
+    });
+    let computed;
+    let blocks = match signature {
+        Some(v) => &v.blocks,
+        None => {
+            let mut blocks = Vec::new();
+            _for_each_block(&mut Throttled(&mut old), |data| {
+                blocks.push(BlockSum::of(data))
+            })?;
+            computed = blocks;
+            &computed
+        }
+    };
+
+    local.write_atomic(to, |temp| {
+        copy::copy(to, temp, Reflink::Auto)?;
+        let mut out = Output {
+            file: OpenOptions::new().write(true).open(temp)?,
+            old,
+            offset: 0,
+            written: 0,
+            buf: vec![0; BLOCK_SIZE],
+        };
+        _patch(&mut reader, &mut out, blocks, old_meta.len())?;
+        out.file.set_len(out.offset)?;
+        out.file
+            .set_permissions(reader.0.metadata()?.permissions())?;
+        Ok(out.written)
+    })
+}
+
+/// Writes everything read from `reader` to `out`, reusing the `blocks` of the old destination
+/// of `old_len` bytes wherever the data read matches them.
+fn _patch(
+    reader: &mut impl Read,
+    out: &mut Output,
+    blocks: &[BlockSum],
+    old_len: u64,
+) -> io::Result<()> {
+    let mut by_weak: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (i, block) in blocks.iter().enumerate() {
+        by_weak.entry(block.weak).or_default().push(i);
+    }
+    let block_len = |i: usize| (old_len - (i * BLOCK_SIZE) as u64).min(BLOCK_SIZE as u64);
+
+    // Source data is read into `buf` in chunks. `pos` is the start of the window, everything
+    // from `literal` up to it wasn't written yet.
+    let mut buf = Vec::new();
+    let (mut literal, mut pos) = (0, 0);
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+    loop {
+        // Keeps the window and the block after it in memory.
+        if !eof && buf.len() - pos <= 2 * BLOCK_SIZE {
+            buf.drain(..literal);
+            pos -= literal;
+            literal = 0;
+            let start = buf.len();
+            buf.resize(start + 4 * BLOCK_SIZE, 0);
+            let n = _read_block(reader, &mut buf[start..])?;
+            buf.truncate(start + n);
+            eof = n < 4 * BLOCK_SIZE;
+        }
+        let len = (buf.len() - pos).min(BLOCK_SIZE);
+        if len == 0 {
+            break;
+        }
+        let window = &buf[pos..pos + len];
+        let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+        let target = out.offset + (pos - literal) as u64;
+
+        // A matching block that's already at `target` is preferred, it doesn't have to be
+        // written.
+        let found = by_weak.get(&weak).and_then(|candidates| {
+            let mut usable = candidates
+                .iter()
+                .copied()
+                .filter(|&i| block_len(i) == len as u64)
+                .peekable();
+            usable.peek()?;
+            let strong = <BlockHash>::from(Sha256::digest(window));
+            let matching: Vec<_> = usable.filter(|&i| blocks[i].strong == strong).collect();
+            matching
+                .iter()
+                .copied()
+                .find(|&i| (i * BLOCK_SIZE) as u64 == target)
+                .or(matching.first().copied())
+        });
+        if let Some(i) = found {
+            // Moving a block costs as much as writing it, so if the source lines up with the
+            // destination again at the next block boundary it's written as is up to there.
+            let aligned = target.next_multiple_of(BLOCK_SIZE as u64);
+            let j = aligned as usize / BLOCK_SIZE;
+            let start = literal + (aligned - out.offset) as usize;
+            if (i * BLOCK_SIZE) as u64 != target
+                && j < blocks.len()
+                && start + block_len(j) as usize <= buf.len()
+                && blocks[j].strong
+                    == <BlockHash>::from(Sha256::digest(&buf[start..start + block_len(j) as usize]))
+            {
+                out.literal(&buf[literal..start])?;
+                pos = start;
+                literal = start;
+                rolling = None;
+                continue;
+            }
+            out.literal(&buf[literal..pos])?;
+            out.reuse((i * BLOCK_SIZE) as u64, len)?;
+            pos += len;
+            literal = pos;
+            rolling = None;
+            continue;
+        }
+
+        let next = buf.get(pos + BLOCK_SIZE).copied();
+        rolling.as_mut().unwrap().roll(buf[pos], next);
+        pos += 1;
+        if pos - literal == BLOCK_SIZE {
+            out.literal(&buf[literal..pos])?;
+            literal = pos;
+        }
+    }
+    out.literal(&buf[literal..pos])
+}
@@ -0,0 +1,237 @@
+use crate::delta::{self, BlockSum};
+use crate::metadata::{mtime_ns, FileMeta};
+use rusqlite::{Connection, OptionalExtension, Result};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::{self, Metadata};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How many journal writes are grouped into a single commit.
+const BATCH_SIZE: usize = 256;
+/// Upper bound on how long a journal write can stay uncommitted.
+const BATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A file that was already copied by an earlier, interrupted run.
+#[derive(Debug, Clone)]
+pub struct JournalEntry {
+    pub dest: PathBuf,
+    pub size: u64,
+    pub mtime: i64,
+    pub verified: bool,
+}
+
+/// Progress of a backup that is journaled to the database while the run is going.
+///
+/// Writes are committed in small batches so an interrupted run only loses the last
+/// couple of seconds of progress. The journal is cleared once the run completes.
+pub struct Journal<'a> {
+    conn: &'a Connection,
+    backup_id: u64,
+    pending: Cell<usize>,
+    last_commit: Cell<Instant>,
+}
+
+impl<'a> Journal<'a> {
+    pub fn begin(conn: &'a Connection, backup_id: u64) -> Result<Self> {
+        conn.execute_batch("BEGIN")?;
+        Ok(Self {
+            conn,
+            backup_id,
+            pending: Cell::new(0),
+            last_commit: Cell::new(Instant::now()),
+        })
+    }
+
+    /// Records that `from` was copied to `to`.
+    pub fn copied(&self, from: &Path, to: &Path) -> Result<()> {
+        let (size, mtime) = match fs::metadata(from) {
+            Ok(m) => (m.len(), mtime_ns(&m)),
+            Err(_) => return Ok(()),
+        };
+        self.conn.execute(
+            "INSERT OR REPLACE INTO Journal (backup_id, source, dest, size, mtime, verified)
+            VALUES (?1, ?2, ?3, ?4, ?5, 0)",
+            (
+                self.backup_id as i64,
+                from.display().to_string(),
+                to.display().to_string(),
+                size as i64,
+                mtime,
+            ),
+        )?;
+        self.tick()
+    }
+
+    /// Records `from` and its hash in the `Files` table.
+    pub fn hashed(&self, from: &Path, to: &Path, sha256: &str, meta: &FileMeta) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO Files
+            (backup_id, source, dest, sha256, size, mtime, ctime, mode, inode, kind)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            (
+                self.backup_id as i64,
+                from.display().to_string(),
+                to.display().to_string(),
+                sha256,
+                meta.size as i64,
+                meta.mtime,
+                meta.ctime,
+                meta.mode,
+                meta.inode.map(|v| v as i64),
+                meta.kind.as_str(),
+            ),
+        )?;
+        self.tick()
+    }
+
+    /// Records the block checksums of `from` in the `Blocks` table. They aren't trusted until
+    /// [`Journal::blocks_verified`] is called.
+    pub fn blocks(&self, from: &Path, block_size: usize, blocks: &[BlockSum]) -> Result<()> {
+        let (hashes, weak) = delta::to_blobs(blocks);
+        self.conn.execute(
+            "INSERT OR REPLACE INTO Blocks (backup_id, source, block_size, hashes, weak)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            (
+                self.backup_id as i64,
+                from.display().to_string(),
+                block_size as i64,
+                hashes,
+                weak,
+            ),
+        )?;
+        self.tick()
+    }
+
+    /// Records the size and modification time of `to` once it was verified to match the
+    /// block hashes of `from`.
+    pub fn blocks_verified(&self, from: &Path, to: &Path) -> Result<()> {
+        let meta = match fs::metadata(to) {
+            Ok(v) => v,
+            Err(_) => return Ok(()),
+        };
+        self.conn.execute(
+            "UPDATE Blocks SET dest_size = ?3, dest_mtime = ?4 WHERE backup_id = ?1 AND source = ?2",
+            (
+                self.backup_id as i64,
+                from.display().to_string(),
+                meta.len() as i64,
+                mtime_ns(&meta),
+            ),
+        )?;
+        self.tick()
+    }
+
+    /// Marks `from` as copied and verified, so a resumed run can skip it entirely.
+    pub fn verified(&self, from: &Path) -> Result<()> {
+        self.conn.execute(
+            "UPDATE Journal SET verified = 1 WHERE backup_id = ?1 AND source = ?2",
+            (self.backup_id as i64, from.display().to_string()),
+        )?;
+        self.tick()
+    }
+
+    /// Commits pending writes and clears the journal. Called once the run is complete.
+    pub fn finish(self) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM Journal WHERE backup_id = ?1",
+            [self.backup_id as i64],
+        )?;
+        self.conn.execute_batch("COMMIT")
+    }
+
+    fn tick(&self) -> Result<()> {
+        self.pending.set(self.pending.get() + 1);
+        if self.pending.get() >= BATCH_SIZE || self.last_commit.get().elapsed() >= BATCH_INTERVAL {
+            self.conn.execute_batch("COMMIT; BEGIN")?;
+            self.pending.set(0);
+            self.last_commit.set(Instant::now());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Journal<'_> {
+    fn drop(&mut self) {
+        // Keep whatever progress was made if the run bails out early.
+        if !self.conn.is_autocommit() {
+            let _ = self.conn.execute_batch("COMMIT");
+        }
+    }
+}
+
+/// Progress left behind by an interrupted run of a backup, keyed by source path.
+pub struct Resume {
+    entries: HashMap<PathBuf, JournalEntry>,
+}
+
+impl Resume {
+    pub fn load(conn: &Connection, backup_id: u64) -> Result<Self> {
+        let mut stmt = conn.prepare(
+            "SELECT source, dest, size, mtime, verified FROM Journal WHERE backup_id = ?1",
+        )?;
+        let entries = stmt
+            .query_map([backup_id as i64], |row| {
+                Ok((
+                    PathBuf::from(row.get::<usize, String>(0)?),
+                    JournalEntry {
+                        dest: row.get::<usize, String>(1)?.into(),
+                        size: row.get::<usize, i64>(2)? as u64,
+                        mtime: row.get(3)?,
+                        verified: row.get(4)?,
+                    },
+                ))
+            })?
+            .collect::<Result<HashMap<_, _>>>()?;
+        Ok(Self { entries })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the destination of `from` if an earlier run already copied it and neither
+    /// the source nor the copy changed since.
+    pub fn copied(&self, from: &Path, meta: &Metadata) -> Option<&PathBuf> {
+        let entry = self.entries.get(from)?;
+        if entry.size != meta.len() || entry.mtime != mtime_ns(meta) {
+            return None;
+        }
+        match fs::metadata(&entry.dest) {
+            Ok(m) if m.len() == entry.size => Some(&entry.dest),
+            _ => None,
+        }
+    }
+
+    /// Whether `from` was already hashed and verified by an earlier run.
+    pub fn verified(&self, from: &Path) -> bool {
+        match (self.entries.get(from), fs::metadata(from)) {
+            (Some(entry), Ok(meta)) => entry.verified && self.copied(from, &meta).is_some(),
+            _ => false,
+        }
+    }
+}
+
+/// Returns the ids of the backups that have an interrupted run.
+pub fn interrupted(conn: &Connection) -> Result<Vec<u64>> {
+    let mut stmt = conn.prepare("SELECT DISTINCT backup_id FROM Journal")?;
+    let ids = stmt
+        .query_map((), |row| Ok(row.get::<usize, i64>(0)? as u64))?
+        .collect();
+    ids
+}
+
+/// Whether the backup with `id` has an interrupted run.
+pub fn is_interrupted(conn: &Connection, id: u64) -> Result<bool> {
+    conn.query_row(
+        "SELECT 1 FROM Journal WHERE backup_id = ?1 LIMIT 1",
+        [id as i64],
+        |_| Ok(()),
+    )
+    .optional()
+    .map(|v| v.is_some())
+}
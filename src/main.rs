@@ -1,25 +1,48 @@
+mod archive;
+mod catalog;
 mod commands;
+mod copy;
+mod delta;
+mod journal;
+mod manifest;
+mod metadata;
+mod migrations;
+mod mirror;
+mod pool;
+mod ratelimit;
+mod remote;
+mod runs;
+mod s3;
+mod storage;
 mod test;
 
 use fdlimit::{raise_fd_limit, Outcome};
 use indicatif_log_bridge::LogWrapper;
-use rusqlite::{Connection, Transaction};
+use rusqlite::{Connection, OptionalExtension};
 use sha2::{Digest, Sha256};
 
+use crate::archive::{Compression, Format};
 use crate::commands::*;
-use clap::{Parser, Subcommand};
+use crate::copy::Reflink;
+use crate::delta::Signatures;
+use crate::journal::{Journal, Resume};
+use crate::metadata::FileMeta;
+use crate::storage::StorageBackend;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
 use colored::Colorize;
 use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use log::{error, info};
 use std::collections::VecDeque;
 use std::ffi::OsString;
+use std::fmt;
 use std::fs::{DirEntry, File, ReadDir};
 use std::hash::{Hash, Hasher};
 use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::string::ToString;
-use std::sync::mpsc;
 use std::sync::mpsc::Sender;
+use std::sync::{mpsc, Arc};
 use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 use std::{fs, io};
@@ -30,14 +53,16 @@ struct Conclusion {
     pub error_count: usize,
     pub error_list: Vec<String>,
     pub total_size: FileSize,
-    pub path_list: Vec<(PathBuf, PathBuf)>,
+    pub path_list: Vec<(PathBuf, PathBuf, FileMeta)>,
 }
 
 enum ConclusionFields {
     TotalCount(usize),
     Error(String),
     FileSize(FileSize),
-    PathCouple((PathBuf, PathBuf)),
+    PathCouple((PathBuf, PathBuf, FileMeta)),
+    /// A file that was already copied by an interrupted run.
+    Resumed((PathBuf, PathBuf, FileMeta)),
 }
 
 #[derive(Copy, Clone)]
@@ -58,6 +83,16 @@ impl Conclusion {
             path_list: Vec::new(),
         }
     }
+
+    /// Adds the results of copying another source of the same backup.
+    pub fn merge(&mut self, other: Conclusion) {
+        self.total_count += other.total_count;
+        self.error_count += other.error_count;
+        self.error_list.extend(other.error_list);
+        self.total_size.byte += other.total_size.byte;
+        self.total_size.update();
+        self.path_list.extend(other.path_list);
+    }
 }
 
 impl FileSize {
@@ -82,19 +117,19 @@ impl FileSize {
         self.mb = self.kb / 1024;
         self.gb = self.mb / 1024;
     }
+}
 
-    pub fn to_string(&self) -> String {
-        let mut size_str = String::new();
+impl fmt::Display for FileSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if self.gb != 0 {
-            size_str += &format!("{:.2} GB", self.mb as f64 / 1024.0);
+            write!(f, "{:.2} GB", self.mb as f64 / 1024.0)
         } else if self.mb != 0 {
-            size_str += &format!("{} MB", self.mb)
+            write!(f, "{} MB", self.mb)
         } else if self.kb != 0 {
-            size_str += &format!("{} KB", self.kb)
+            write!(f, "{} KB", self.kb)
         } else {
-            size_str += &format!("{} Bytes", self.byte)
+            write!(f, "{} Bytes", self.byte)
         }
-        size_str
     }
 }
 
@@ -112,6 +147,14 @@ impl From<u64> for FileSize {
 struct Args {
     #[command(subcommand)]
     command: Commands,
+
+    #[arg(long, global = true, value_parser = parse_size)]
+    /// Limits copy, hash and verify reads to this many bytes per second, e.g. "50M"
+    bwlimit: Option<u64>,
+
+    #[arg(long, global = true, requires = "bwlimit")]
+    /// Only applies --bwlimit during these times of day, e.g. "08:00-18:00,20:00-22:00"
+    bwlimit_hours: Option<ratelimit::Schedule>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -126,21 +169,247 @@ enum Commands {
     Revert {
         id: u64,
 
-        #[arg(short, long)]
-        /// Enables multithreading. This feature is not complete and can be unstable
-        multithread: bool,
+        #[command(flatten)]
+        opts: CopyOptions,
     },
     /// Creates a backup
+    #[command(override_usage = "hardcpy create [OPTIONS] <SOURCES>... <DEST>
+       hardcpy create [OPTIONS] --files-from <LIST> [SOURCES]... <DEST>")]
     Create {
-        source: PathBuf,
-        dest: PathBuf,
+        #[arg(required = true, value_name = "PATH")]
+        /// Directories and files to back up, followed by the destination. Directories are
+        /// copied into a directory named after them, files into the root of the destination
+        paths: Vec<PathBuf>,
 
-        #[arg(short, long)]
-        /// Enables multithreading. This feature is not complete and can be unstable
-        multithread: bool,
+        #[arg(long, value_name = "LIST")]
+        /// Also backs up every path listed in LIST, one per line. "-" reads the list from stdin
+        files_from: Option<PathBuf>,
+
+        #[command(flatten)]
+        opts: CopyOptions,
     },
     /// Verifies that the tracked source files match destination files
-    Verify { id: u64 },
+    Verify {
+        id: u64,
+
+        #[arg(short, long, default_value_t = 1)]
+        /// Number of files verified at once
+        jobs: usize,
+    },
+    /// Removes file entries that don't belong to any backup
+    Gc,
+    /// Receives files from `create --remote` over stdin and stdout, e.g. when run through ssh
+    Serve {
+        #[arg(long)]
+        /// Only allows writing below this directory, with paths relative to it
+        root: Option<PathBuf>,
+    },
+    /// Verifies a directory against a sha256sum or BSD style checksum file, or a hardcpy
+    /// manifest. Doesn't need the catalog
+    Check {
+        dir: PathBuf,
+
+        #[arg(short, long)]
+        /// The checksum file. Paths in it are relative to dir
+        sums: PathBuf,
+
+        #[arg(short, long)]
+        /// Lists matching files too
+        all: bool,
+    },
+    /// Takes over an existing destination, e.g. one made with cp or rsync or whose catalog was
+    /// lost, by hashing its files and recording them as a backup of source
+    Adopt { source: PathBuf, dest: PathBuf },
+    /// Rebuilds the catalog entries of a backup by hashing the files in its destination
+    Reindex { id: u64 },
+    /// Shows how the source of a backup changed since it was backed up. Doesn't copy anything
+    Diff {
+        id: u64,
+
+        #[arg(short, long)]
+        /// Only compares sizes and modification times instead of hashing every file
+        quick: bool,
+
+        #[arg(short, long)]
+        /// Lists unchanged files too
+        all: bool,
+    },
+    /// Compares the files tracked by two backups using the hashes in the catalog
+    Compare {
+        a: u64,
+        b: u64,
+
+        #[arg(short, long)]
+        /// Lists identical files too
+        all: bool,
+    },
+    /// Searches every backup for files by name, glob pattern or hash
+    Find {
+        pattern: String,
+
+        #[arg(short, long, value_enum, default_value_t = FindMode::Auto)]
+        /// How the pattern is matched
+        mode: FindMode,
+
+        #[arg(short = 'H', long)]
+        /// Shows the SHA-256 hash of each file
+        hashes: bool,
+    },
+    /// Lists the files tracked by a backup
+    Show {
+        id: u64,
+
+        #[arg(short, long)]
+        /// Shows the files as a tree
+        tree: bool,
+
+        #[arg(short, long)]
+        /// Only shows files under this path, relative to the destination
+        prefix: Option<PathBuf>,
+
+        #[arg(short, long)]
+        /// Only shows files whose path matches this glob pattern, e.g. "**/*.pdf"
+        glob: Option<String>,
+
+        #[arg(short = 'H', long)]
+        /// Shows the SHA-256 hash of each file
+        hashes: bool,
+    },
+    /// Shows the past runs of a backup, or of every backup if no id is given
+    History {
+        id: Option<u64>,
+
+        #[arg(short = 'n', long, default_value_t = 20)]
+        /// Maximum number of runs to show
+        limit: usize,
+
+        #[arg(short, long)]
+        /// Lists the errors of each run
+        errors: bool,
+    },
+    /// Exports or imports the catalog of backups, files and runs
+    Catalog {
+        #[command(subcommand)]
+        action: CatalogAction,
+    },
+    /// Resumes an interrupted backup. Resumes every interrupted backup if no id is given
+    Resume {
+        id: Option<u64>,
+
+        #[command(flatten)]
+        opts: CopyOptions,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CatalogAction {
+    /// Writes the catalog as JSON to a file, or to stdout if no file is given
+    Export {
+        output: Option<PathBuf>,
+
+        #[arg(long)]
+        /// Writes one backup per line (NDJSON) instead of a single document
+        ndjson: bool,
+    },
+    /// Merges a catalog written by `catalog export` into this one
+    Import { input: PathBuf },
+}
+
+/// Options shared by every command that copies files.
+#[derive(clap::Args, Debug, Clone, Default)]
+struct CopyOptions {
+    #[arg(short, long)]
+    /// Enables multithreading. This feature is not complete and can be unstable
+    multithread: bool,
+
+    #[arg(long)]
+    /// Flushes every copied file to disk before moving it into place
+    fsync: bool,
+
+    #[arg(long)]
+    /// Only writes the changed blocks of large files that already exist in the destination, into
+    /// a clone of them that replaces them once it's complete. On filesystems without reflinks
+    /// the clone is a full copy made within the destination
+    delta: bool,
+
+    #[arg(long, value_enum, default_value_t = Reflink::Never)]
+    /// Clones files instead of copying them on filesystems that support it, e.g. btrfs or XFS
+    reflink: Reflink,
+
+    #[arg(short, long)]
+    /// Number of files hashed and verified at once. Defaults to one per core with
+    /// --multithread, otherwise one
+    jobs: Option<usize>,
+
+    #[arg(long)]
+    /// Doesn't write a sha256sum manifest into the destination root
+    no_manifest: bool,
+
+    #[arg(long, value_enum, default_value_t = Format::Dir)]
+    /// How the files are stored in the destination
+    format: Format,
+
+    #[arg(long, value_enum, requires = "format")]
+    /// Compresses the archives. Only applies to --format tar
+    compression: Option<Compression>,
+
+    #[arg(long, value_parser = parse_size)]
+    /// Starts a new archive once one grows past this size, e.g. "4G". Only applies to --format tar
+    split_size: Option<u64>,
+
+    #[arg(long, value_name = "COMMAND", conflicts_with_all = ["format", "delta", "reflink"])]
+    /// Sends the files to a peer started with this command instead, e.g.
+    /// "ssh nas hardcpy serve". The destination is a path on the peer
+    remote: Option<String>,
+
+    #[arg(long, visible_alias = "delete", conflicts_with_all = ["format", "remote"])]
+    /// Removes the copies of files that were deleted from the source
+    mirror: bool,
+
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        requires = "mirror",
+        value_parser = clap::value_parser!(u32).range(0..=100)
+    )]
+    /// Removes nothing if more than this share of the tracked files would be removed by
+    /// --mirror. Defaults to 50
+    max_delete: Option<u32>,
+}
+
+impl CopyOptions {
+    fn jobs(&self) -> usize {
+        self.jobs
+            .unwrap_or_else(|| pool::default_jobs(self.multithread))
+    }
+}
+
+/// Parses a size such as "512", "50M" or "4G". Suffixes are powers of 1024.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, ""),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size \"{value}\""))?;
+    let multiplier: u64 = match unit
+        .trim()
+        .to_ascii_uppercase()
+        .trim_end_matches("IB")
+        .trim_end_matches('B')
+    {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("invalid size unit \"{unit}\"")),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size \"{value}\" is too large"))
 }
 
 #[derive(Debug)]
@@ -149,86 +418,376 @@ struct BackupEntry {
     from: PathBuf,
     to: PathBuf,
     compression: Option<String>,
+    /// The command reaching the peer, for backups sent with --remote.
+    remote: Option<String>,
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 struct FileEntry {
     backup_id: u64,
     from: PathBuf,
     to: PathBuf,
     sha256: String,
+    /// Unset for files recorded by versions of hardcpy that didn't track metadata.
+    size: Option<u64>,
+    mtime: Option<i64>,
+    /// The archive holding the file, for backups stored as archives.
+    archive: Option<PathBuf>,
+    /// Offset of the file's header in the uncompressed archive stream.
+    offset: Option<u64>,
+}
+
+/// Returns the backup with `id`.
+fn _load_backup(conn: &Connection, id: u64) -> rusqlite::Result<Option<BackupEntry>> {
+    conn.query_row(
+        "SELECT id, source, dest, compression, remote FROM Backups WHERE id = ?1",
+        [id as i64],
+        |row| {
+            Ok(BackupEntry {
+                id: row.get::<usize, i64>(0)? as u64,
+                from: row.get::<usize, String>(1)?.into(),
+                to: row.get::<usize, String>(2)?.into(),
+                compression: row.get(3)?,
+                remote: row.get(4)?,
+            })
+        },
+    )
+    .optional()
+}
+
+/// Returns the source directories of `backup`, in the order they were given.
+fn _load_sources(conn: &Connection, backup: &BackupEntry) -> rusqlite::Result<Vec<PathBuf>> {
+    let mut stmt =
+        conn.prepare("SELECT source FROM Sources WHERE backup_id = ?1 ORDER BY rowid")?;
+    let sources = stmt
+        .query_map([backup.id as i64], |row| {
+            Ok(PathBuf::from(row.get::<usize, String>(0)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    match sources.is_empty() {
+        true => Ok(vec![backup.from.clone()]),
+        false => Ok(sources),
+    }
+}
+
+/// Returns the files tracked by the backup with `id`, ordered by destination path.
+fn _load_files(conn: &Connection, id: u64) -> rusqlite::Result<Vec<FileEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT source, dest, sha256, size, mtime, archive, offset FROM Files
+        WHERE backup_id = ?1 ORDER BY dest",
+    )?;
+    let files = stmt
+        .query_map([id as i64], |row| {
+            Ok(FileEntry {
+                backup_id: id,
+                from: row.get::<usize, String>(0)?.into(),
+                to: row.get::<usize, String>(1)?.into(),
+                sha256: row.get(2)?,
+                size: row.get::<usize, Option<i64>>(3)?.map(|v| v as u64),
+                mtime: row.get(4)?,
+                archive: row.get::<usize, Option<String>>(5)?.map(PathBuf::from),
+                offset: row.get::<usize, Option<i64>>(6)?.map(|v| v as u64),
+            })
+        })?
+        .collect();
+    files
+}
+
+/// Records a file of the backup with `id` that was copied outside of the journal.
+fn _record_file(
+    conn: &Connection,
+    id: u64,
+    from: &Path,
+    to: &Path,
+    sha256: &str,
+    meta: &FileMeta,
+) -> rusqlite::Result<usize> {
+    conn.execute(
+        "INSERT OR REPLACE INTO Files
+        (backup_id, source, dest, sha256, size, mtime, ctime, mode, inode, kind)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        (
+            id as i64,
+            from.display().to_string(),
+            to.display().to_string(),
+            sha256,
+            meta.size as i64,
+            meta.mtime,
+            meta.ctime,
+            meta.mode,
+            meta.inode.map(|v| v as i64),
+            meta.kind.as_str(),
+        ),
+    )
+}
+
+/// Returns the directory the catalog and `config.ini` are kept in.
+fn _config_dir() -> PathBuf {
+    let mut dir = dirs::config_dir().unwrap_or_else(|| std::env::current_dir().unwrap());
+    dir.push("hardcpy");
+    dir
+}
+
+/// Returns `path` relative to `root`, or `path` itself if it's not inside `root`.
+fn _relative(path: &Path, root: &Path) -> PathBuf {
+    path.strip_prefix(root).unwrap_or(path).to_path_buf()
 }
 
 fn main() {
     let args = Args::parse();
 
-    let mut db_dir = dirs::config_dir().unwrap_or_else(|| {
+    // Stdout carries the protocol, so nothing else may be printed to it and the catalog
+    // isn't needed.
+    if let Commands::Serve { root } = &args.command {
+        if let Err(e) = remote::serve(io::stdin().lock(), io::stdout().lock(), root.as_deref()) {
+            eprintln!("{} {}", "Error:".red().bold(), e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if dirs::config_dir().is_none() {
         println!(
             "{} Couldn't get a config directory, using current directory.",
             "[INFO]".bright_yellow()
         );
-        std::env::current_dir().unwrap()
-    });
-    db_dir.push("hardcpy");
+    }
+    let db_dir = _config_dir();
     fs::create_dir_all(&db_dir).unwrap();
 
-    let mut conn = Connection::open(db_dir.join("backups.db")).unwrap();
-    let mut tx = conn.transaction().unwrap();
-
-    tx.execute(
-        "CREATE TABLE IF NOT EXISTS Backups (
-            id INTEGER PRIMARY KEY,
-            source TEXT NOT NULL,
-            dest TEXT NOT NULL,
-            compression TEXT
-        )",
-        (),
-    )
-    .unwrap();
+    let db_path = db_dir.join("backups.db");
+    let conn = Connection::open(&db_path).unwrap();
+    match migrations::migrate(&conn, Some(&db_path)) {
+        Ok(Some(backup)) => println!(
+            "{} Upgraded the database to version {}. The old one was saved to \"{}\".",
+            "[INFO]".bright_yellow(),
+            migrations::SCHEMA_VERSION,
+            backup.display()
+        ),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("{} {}", "Error:".red().bold(), e);
+            std::process::exit(1);
+        }
+    }
 
-    tx.execute(
-        "CREATE TABLE IF NOT EXISTS Files (
-            backup_id INTEGER NOT NULL,
-            source TEXT NOT NULL,
-            dest TEXT NOT NULL,
-            sha256 TEXT NOT NULL,
-            PRIMARY KEY (source, dest)
-        )",
-        (),
-    )
-    .unwrap();
+    if let Some(rate) = args.bwlimit {
+        ratelimit::install(ratelimit::RateLimiter::new(rate, args.bwlimit_hours));
+    }
 
     match args.command {
-        Commands::List => list(&tx),
-        Commands::SoftDelete { id } => soft_delete(&tx, id),
-        Commands::Delete { id } => delete(&tx, id),
-        Commands::Revert { id, multithread } => revert(&tx, id, multithread),
+        Commands::List => list(&conn),
+        Commands::SoftDelete { id } => soft_delete(&conn, id),
+        Commands::Delete { id } => delete(&conn, id),
+        Commands::Revert { id, opts } => revert(&conn, id, &opts),
         Commands::Create {
-            source,
-            dest,
-            multithread,
+            mut paths,
+            files_from,
+            opts,
         } => {
-            _copy(&tx, multithread, source, dest);
+            let dest = paths.pop().unwrap();
+            let mut sources = paths;
+            if sources.is_empty() && files_from.is_none() {
+                let mut command = Args::command();
+                command.build();
+                command
+                    .find_subcommand_mut("create")
+                    .unwrap()
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        "a source or --files-from is required besides the destination",
+                    )
+                    .exit();
+            }
+            if let Some(list) = files_from {
+                match _read_list(&list) {
+                    Ok(v) => sources.extend(v),
+                    Err(e) => {
+                        eprintln!("{} {} (\"{}\")", "Error:".red().bold(), e, list.display());
+                        std::process::exit(1);
+                    }
+                }
+            }
+            _copy_sources(&conn, &opts, &sources, dest);
         }
-        Commands::Verify { id } => verify(&mut tx, id),
+        Commands::Verify { id, jobs } => verify(&conn, id, jobs),
+        Commands::Gc => gc(&conn),
+        Commands::Serve { .. } => unreachable!(),
+        Commands::Check { dir, sums, all } => {
+            if !check(&dir, &sums, all) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Adopt { source, dest } => adopt(&conn, &source, &dest),
+        Commands::Reindex { id } => reindex(&conn, id),
+        Commands::Diff { id, quick, all } => diff(&conn, id, quick, all),
+        Commands::Compare { a, b, all } => compare(&conn, a, b, all),
+        Commands::Find {
+            pattern,
+            mode,
+            hashes,
+        } => find(&conn, &pattern, mode, hashes),
+        Commands::Show {
+            id,
+            tree,
+            prefix,
+            glob,
+            hashes,
+        } => show(&conn, id, tree, prefix, glob, hashes, &mut io::stdout()).unwrap(),
+        Commands::History { id, limit, errors } => {
+            history(&conn, id, limit, errors, &mut io::stdout()).unwrap()
+        }
+        Commands::Catalog { action } => match action {
+            CatalogAction::Export { output, ndjson } => catalog_export(&conn, output, ndjson),
+            CatalogAction::Import { input } => catalog_import(&conn, &input),
+        },
+        Commands::Resume { id, opts } => resume(&conn, id, &opts),
     }
-    tx.commit().unwrap();
 }
 
-fn _copy(conn: &Transaction, is_multithread: bool, source_str: PathBuf, dest_str: PathBuf) -> bool {
-    let source_name = source_str.iter().last().unwrap().to_owned();
+/// Reads the paths listed in `list`, one per line, or from stdin if it's `-`. Empty lines
+/// are skipped.
+fn _read_list(list: &Path) -> io::Result<Vec<PathBuf>> {
+    let text = match list == Path::new("-") {
+        true => io::read_to_string(io::stdin())?,
+        false => fs::read_to_string(list)?,
+    };
+    Ok(text
+        .lines()
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
 
-    let source = match fs::read_dir(&source_str) {
-        Ok(d) => d,
-        Err(e) => {
-            eprintln!("Error: {} (\"{}\")", e, source_str.display());
-            return true;
+/// Returns the id of the backup of `source` into `dest`.
+fn _backup_id(source: &Path, dest: &Path) -> u64 {
+    let source_name = source.iter().next_back().unwrap();
+    let v = format!(
+        "{}{}",
+        source.display(),
+        dest.join(source_name).to_str().unwrap()
+    );
+    let mut hasher = fnv::FnvHasher::default();
+    v.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Returns the id of a backup of `sources` into `dest`. Backups of a single source keep the
+/// id they always had.
+fn _backup_id_sources(sources: &[PathBuf], dest: &Path) -> u64 {
+    if let [source] = sources {
+        return _backup_id(source, dest);
+    }
+    let mut sorted: Vec<_> = sources.iter().collect();
+    sorted.sort();
+    let mut hasher = fnv::FnvHasher::default();
+    for source in sorted {
+        source.display().to_string().hash(&mut hasher);
+    }
+    dest.display().to_string().hash(&mut hasher);
+    hasher.finish()
+}
+
+fn _copy(conn: &Connection, opts: &CopyOptions, source_str: PathBuf, dest_str: PathBuf) -> bool {
+    _copy_sources(conn, opts, &[source_str], dest_str)
+}
+
+/// Copies every directory and file in `sources` into `dest_str` as a single backup. Each of
+/// them is laid out under its own name, so their names have to differ.
+fn _copy_sources(
+    conn: &Connection,
+    opts: &CopyOptions,
+    sources: &[PathBuf],
+    dest_str: PathBuf,
+) -> bool {
+    let single_dir = matches!(sources, [v] if !v.is_file());
+    if single_dir {
+        if let Some(command) = &opts.remote {
+            return remote::create(conn, opts, command, &sources[0], &dest_str);
         }
+    } else if opts.remote.is_some() || opts.format == Format::Tar {
+        eprintln!(
+            "{} Backups of several sources or of single files can't be sent to a peer or stored as archives",
+            "Error:".red().bold()
+        );
+        return true;
+    }
+
+    let backend: Arc<dyn StorageBackend> = match s3::S3Path::parse(&dest_str) {
+        Some(location) => {
+            let ignored = s3::ignored_options(opts);
+            if !ignored.is_empty() {
+                eprintln!(
+                    "{} {} don't apply to S3 destinations and are ignored",
+                    "Warning:".yellow().bold(),
+                    ignored.join(", ")
+                );
+            }
+            match s3::connect(&location) {
+                Some(v) => Arc::new(v),
+                None => return true,
+            }
+        }
+        None => Arc::new(storage::Local::new(opts)),
     };
+    _copy_to_backend(conn, opts, sources, dest_str, backend)
+}
 
-    match fs::create_dir_all(&dest_str) {
-        Ok(_) => {}
-        Err(e) => {
+/// Copies `sources` into `dest_str` like [`_copy_sources`], through the `backend` holding
+/// `dest_str`.
+fn _copy_to_backend(
+    conn: &Connection,
+    opts: &CopyOptions,
+    sources: &[PathBuf],
+    dest_str: PathBuf,
+    backend: Arc<dyn StorageBackend>,
+) -> bool {
+    let local = s3::S3Path::parse(&dest_str).is_none();
+    let s3_opts;
+    let opts = match local {
+        true => opts,
+        // Objects can't be updated in place and are always stored as they are.
+        false => {
+            s3_opts = CopyOptions {
+                delta: false,
+                format: Format::Dir,
+                ..opts.clone()
+            };
+            &s3_opts
+        }
+    };
+
+    let mut names = Vec::with_capacity(sources.len());
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    for source_str in sources {
+        let source_name = source_str.iter().next_back().unwrap().to_owned();
+        if names.contains(&source_name) {
+            eprintln!(
+                "{} More than one source is named {:?}",
+                "Error:".red().bold(),
+                source_name
+            );
+            return true;
+        }
+        names.push(source_name.clone());
+        match fs::metadata(source_str) {
+            // Files are copied into the root of the destination.
+            Ok(meta) if meta.is_file() => files.push((source_str, FileMeta::from(&meta))),
+            _ => match fs::read_dir(source_str) {
+                Ok(d) => dirs.push((source_name, d)),
+                Err(e) => {
+                    eprintln!("Error: {} (\"{}\")", e, source_str.display());
+                    return true;
+                }
+            },
+        }
+    }
+
+    // Objects are neither stored in directories nor written to temporary files first.
+    if local {
+        if let Err(e) = fs::create_dir_all(&dest_str) {
             eprintln!(
                 "{} {} (\"{}\")",
                 "Error:".red().bold(),
@@ -237,36 +796,122 @@ fn _copy(conn: &Transaction, is_multithread: bool, source_str: PathBuf, dest_str
             );
             return true;
         }
-    }
 
-    let timer = Instant::now();
-    let conclusion;
-    let multi;
-
-    if is_multithread {
-        (conclusion, multi) = multithread(source, PathBuf::from(&dest_str), source_name.clone());
-    } else {
-        (conclusion, multi) = singlethread(source, PathBuf::from(&dest_str), source_name.clone());
+        let mut removed = 0;
+        for (source_name, _) in &dirs {
+            match _remove_temp_files(&dest_str.join(source_name)) {
+                Ok(n) => removed += n,
+                Err(e) => eprintln!("{} {}", "Error:".red().bold(), e),
+            }
+        }
+        // Single files are copied into the root of the destination, so is their temporary file.
+        for (source_str, _) in &files {
+            let temp = _temp_path(&dest_str.join(source_str.file_name().unwrap()));
+            match fs::remove_file(&temp) {
+                Ok(()) => removed += 1,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => eprintln!("{} {} (\"{}\")", "Error:".red().bold(), e, temp.display()),
+            }
+        }
+        if removed > 0 {
+            println!(
+                "{} Removed {} leftover temporary files from an earlier run.",
+                "[INFO]".bright_yellow(),
+                removed
+            );
+        }
     }
 
-    let v = format!(
-        "{}{}",
-        source_str.display(),
-        dest_str.join(source_name.clone()).to_str().unwrap()
-    );
-    let mut hasher = fnv::FnvHasher::default();
-    v.hash(&mut hasher);
-    let h = hasher.finish();
+    let h = _backup_id_sources(sources, &dest_str);
     conn.execute(
-        "INSERT OR REPLACE INTO Backups (id, source, dest, compression) VALUES (?1, ?2, ?3, ?4)",
+        "INSERT INTO Backups (id, source, dest, compression) VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT (id) DO UPDATE SET source = excluded.source, dest = excluded.dest,
+        compression = excluded.compression",
         (
             h as i64,
-            source_str.display().to_string(),
+            sources[0].display().to_string(),
             dest_str.display().to_string(),
-            None::<String>,
+            archive::format_name(opts),
         ),
     )
     .unwrap();
+    if sources.len() > 1 {
+        for source_str in sources {
+            conn.execute(
+                "INSERT OR IGNORE INTO Sources (backup_id, source) VALUES (?1, ?2)",
+                (h as i64, source_str.display().to_string()),
+            )
+            .unwrap();
+        }
+    }
+
+    if opts.format == Format::Tar {
+        return archive::create(conn, opts, h, &sources[0], &dest_str);
+    }
+
+    let resume = Arc::new(Resume::load(conn, h).unwrap());
+    let signatures = Arc::new(match opts.delta {
+        true => Signatures::load(conn, h).unwrap(),
+        false => Signatures::default(),
+    });
+    if !resume.is_empty() {
+        println!(
+            "{} Resuming interrupted backup {}. {} files were already copied.",
+            "[INFO]".bright_yellow(),
+            h,
+            resume.len()
+        );
+    }
+    let timer = Instant::now();
+    let run_id = runs::start(conn, h).unwrap();
+    let journal = Journal::begin(conn, h).unwrap();
+
+    let mut conclusion = Conclusion::new();
+    let mut multi = None;
+
+    for (source_name, source) in dirs {
+        let (c, m) = if opts.multithread {
+            multithread(
+                source,
+                PathBuf::from(&dest_str),
+                source_name,
+                &journal,
+                resume.clone(),
+                signatures.clone(),
+                backend.clone(),
+                Arc::new(opts.clone()),
+            )
+        } else {
+            singlethread(
+                source,
+                PathBuf::from(&dest_str),
+                source_name,
+                &journal,
+                &resume,
+                &signatures,
+                backend.as_ref(),
+                opts,
+            )
+        };
+        conclusion.merge(c);
+        multi = Some(m);
+    }
+    if !files.is_empty() || multi.is_none() {
+        let (c, m) = _copy_files(
+            files,
+            &dest_str,
+            &journal,
+            &resume,
+            &signatures,
+            backend.as_ref(),
+            opts,
+        );
+        conclusion.merge(c);
+        multi = Some(m);
+    }
+    let multi = multi.unwrap();
+    // Later steps add their errors to the same list, so copy failures are counted now.
+    let copied_count = conclusion.total_count - conclusion.error_count;
 
     multi.clear().unwrap();
     multi.set_move_cursor(true);
@@ -291,36 +936,62 @@ fn _copy(conn: &Transaction, is_multithread: bool, source_str: PathBuf, dest_str
         info!("Increased max files open limit from {} to {}", from, to);
     }
 
-    for (from, to) in conclusion.path_list {
-        let mut read_from = File::open(from.clone()).unwrap();
-        let mut hasher = Sha256::new();
-
-        info!("{} \"{}\"", "Hashing".green().bold(), from.display());
-        let file_size = read_from.metadata().unwrap().len();
-        let max_buf_size = 1024 * 1024 * 1024 * 4;
-        let buf_size = file_size.min(max_buf_size);
-        let mut buf = Vec::with_capacity(buf_size as usize);
-        while read_from.read_to_end(&mut buf).unwrap() > 0 {
-            hasher.update(&buf);
-        }
-
-        conn.execute(
-            r#"INSERT OR REPLACE INTO Files (backup_id, source, dest, sha256) VALUES (?1, ?2, ?3, ?4)
-        "#,
-            (
-                h as i64,
-                from.display().to_string(),
-                to.display().to_string(),
-                format!("{:x}", hasher.finalize()),
-            ),
-        )
-        .unwrap();
-        pb.inc(1);
-    }
+    let jobs = opts.jobs();
+    let (verified, to_hash): (Vec<_>, Vec<_>) = conclusion
+        .path_list
+        .into_iter()
+        .partition(|(from, _, _)| resume.verified(from));
+    pb.inc(verified.len() as u64);
+    pool::ordered(
+        jobs,
+        &to_hash,
+        |(from, _, meta)| {
+            info!("{} \"{}\"", "Hashing".green().bold(), from.display());
+            match opts.delta && meta.size >= delta::MIN_SIZE {
+                true => delta::hash_blocks(from).map(|(hash, blocks)| (hash, Some(blocks))),
+                false => _hash_file(from).map(|hash| (hash, None)),
+            }
+        },
+        |(from, to, meta), hashes| {
+            match hashes {
+                Ok((hash, blocks)) => {
+                    journal.hashed(from, to, &hash, meta).unwrap();
+                    if let Some(blocks) = blocks {
+                        journal.blocks(from, delta::BLOCK_SIZE, &blocks).unwrap();
+                    }
+                }
+                Err(e) => {
+                    let err = format!("Couldn't hash {:#?} because of error: {e}\n", from);
+                    error!("{}", err);
+                    conclusion.error_count += 1;
+                    conclusion.error_list.push(err);
+                }
+            }
+            pb.inc(1);
+        },
+    );
     pb.finish();
     multi.remove(&pb);
     t.join().unwrap();
 
+    // Done before verifying, which would try to copy the deleted files again.
+    let mut removed = Vec::new();
+    if opts.mirror {
+        let max_delete = opts.max_delete.unwrap_or(mirror::DEFAULT_MAX_DELETE);
+        match mirror::remove_stale(conn, h, backend.as_ref(), &dest_str, max_delete) {
+            Ok(v) => removed = v,
+            Err(e) => {
+                let err = format!(
+                    "Couldn't mirror {} because of error: {e}\n",
+                    dest_str.display()
+                );
+                error!("{}", err);
+                conclusion.error_count += 1;
+                conclusion.error_list.push(err);
+            }
+        }
+    }
+
     let pb = multi.add(ProgressBar::new(conclusion.total_count as u64));
 
     pb.set_style(
@@ -337,75 +1008,72 @@ fn _copy(conn: &Transaction, is_multithread: bool, source_str: PathBuf, dest_str
     let pb_clone = pb.clone();
     let t = _pb_update(pb_clone);
 
-    let mut stmt = conn
-        .prepare("SELECT source, dest, sha256 FROM Files WHERE backup_id = ?1")
-        .unwrap();
-    let iter = stmt
-        .query_map([h as i64], |row| {
-            Ok(FileEntry {
-                backup_id: h,
-                from: row.get::<usize, String>(0).unwrap().into(),
-                to: row.get::<usize, String>(1).unwrap().into(),
-                sha256: row.get_unwrap(2),
-            })
-        })
-        .unwrap();
-
-    for entry in iter {
-        let entry = entry.unwrap();
-        let mut read_from = File::open(&entry.to).unwrap();
-        let mut hasher = Sha256::new();
-
-        info!(
-            "{} \"{}\"",
-            "Verifying".green().bold(),
-            entry.to.display().to_string()
-        );
-        let file_size = read_from.metadata().unwrap().len();
-        let max_buf_size = 1024 * 1024 * 1024 * 4;
-        let buf_size = file_size.min(max_buf_size);
-        let mut buf = Vec::with_capacity(buf_size as usize);
-        while read_from.read_to_end(&mut buf).unwrap() > 0 {
-            hasher.update(&buf);
-        }
-
-        if format!("{:x}", hasher.finalize()) != entry.sha256 {
-            info!(
-                "\n{} \"{}\"",
-                "Copying".green().bold(),
-                entry.to.display().to_string()
+    let entries = _load_files(conn, h).unwrap();
+
+    let (verified, to_verify): (Vec<_>, Vec<_>) = entries
+        .into_iter()
+        .partition(|entry| resume.verified(&entry.from));
+    pb.inc(verified.len() as u64);
+    pool::ordered(
+        jobs,
+        &to_verify,
+        |entry| storage::verify_entry(backend.as_ref(), entry),
+        |entry, result| {
+            match result {
+                Ok(_) => {
+                    journal.verified(&entry.from).unwrap();
+                    if opts.delta {
+                        journal.blocks_verified(&entry.from, &entry.to).unwrap();
+                    }
+                }
+                Err(e) => {
+                    let err = format!("Couldn't verify {:#?} because of error: {e}\n", entry.to);
+                    error!("{}", err);
+                    conclusion.error_count += 1;
+                    conclusion.error_list.push(err);
+                }
+            }
+            pb.inc(1);
+        },
+    );
+    pb.finish();
+    t.join().unwrap();
+    journal.finish().unwrap();
+
+    if local && !opts.no_manifest {
+        let backup = _load_backup(conn, h).unwrap().unwrap();
+        if let Err(e) = manifest::write(conn, &backup) {
+            let err = format!(
+                "Couldn't write the manifest of {} because of error: {e}\n",
+                backup.to.display()
             );
-            fs::copy(entry.from, entry.to).unwrap();
+            error!("{}", err);
+            conclusion.error_count += 1;
+            conclusion.error_list.push(err);
         }
-        pb.inc(1);
     }
-    pb.finish();
-    t.join().unwrap();
 
     // Formatting the size info.
     let size_str = conclusion.total_size.to_string();
 
-    // Formatting the elapsed time.
-    let mut elapsed_str = String::new();
     let elapsed = timer.elapsed();
-    let ms = elapsed.as_millis();
-    let sec_f64 = elapsed.as_secs_f64();
-    let sec = ms / 1000;
-    let min = sec / 60;
-    let hr = min / 60;
+    runs::finish(
+        conn,
+        run_id,
+        elapsed,
+        conclusion.total_size.byte as u64,
+        conclusion.total_count,
+        copied_count,
+        &conclusion.error_list,
+    )
+    .unwrap();
 
-    if hr != 0 {
-        elapsed_str += &format!("{} Hours {}", hr, min % 60);
-    } else if min != 0 {
-        elapsed_str += &format!("{} Minutes {} Seconds", min, sec % 60);
-    } else {
-        elapsed_str += &format!("{:.1} Seconds", sec_f64);
-    }
+    let elapsed_str = _format_duration(elapsed);
 
     println!(
         "\n\n{} {} files {}{}{} in {} {}{}{}",
         "Copied".green().bold(),
-        conclusion.total_count - conclusion.error_count,
+        copied_count,
         "(".truecolor(150, 150, 150),
         size_str.truecolor(150, 150, 150),
         ")".truecolor(150, 150, 150),
@@ -415,7 +1083,18 @@ fn _copy(conn: &Transaction, is_multithread: bool, source_str: PathBuf, dest_str
         " errors)".truecolor(150, 150, 150),
     );
 
-    if conclusion.error_list.len() > 0 {
+    if !removed.is_empty() {
+        println!(
+            "{} {} files that were deleted from the source:",
+            "Removed".red().bold(),
+            removed.len()
+        );
+        for path in &removed {
+            println!("    {}", path.display());
+        }
+    }
+
+    if !conclusion.error_list.is_empty() {
         let log_folder = dirs::config_dir()
             .unwrap_or(std::env::current_dir().unwrap())
             .join("hardcpy/logs");
@@ -434,10 +1113,87 @@ fn _copy(conn: &Transaction, is_multithread: bool, source_str: PathBuf, dest_str
     false
 }
 
-fn singlethread(src: ReadDir, dest: PathBuf, src_name: OsString) -> (Conclusion, MultiProgress) {
+/// Formats the elapsed time.
+fn _format_duration(elapsed: Duration) -> String {
+    let ms = elapsed.as_millis();
+    let sec_f64 = elapsed.as_secs_f64();
+    let sec = ms / 1000;
+    let min = sec / 60;
+    let hr = min / 60;
+
+    if hr != 0 {
+        format!("{} Hours {} Minutes", hr, min % 60)
+    } else if min != 0 {
+        format!("{} Minutes {} Seconds", min, sec % 60)
+    } else {
+        format!("{:.1} Seconds", sec_f64)
+    }
+}
+
+/// Something found while walking a source tree.
+enum Discovered {
+    File(DirEntry, FileMeta),
+    /// Too many file handles are open. Some of the pending directories were dropped.
+    FdLimit,
+}
+
+/// Walks `src` breadth-first, calling `on_discovered` for every file found in it.
+fn _discover(src: ReadDir, mut on_discovered: impl FnMut(Discovered)) {
     let mut stack = VecDeque::new();
     stack.push_front(src);
-    let mut file_list: VecDeque<(DirEntry, &OsString, &PathBuf)> = VecDeque::new();
+
+    while let Some(curr_dir) = stack.pop_front() {
+        for entry in curr_dir {
+            let entry = entry.unwrap();
+            let entry_path = entry.path();
+
+            if entry.file_type().unwrap().is_dir() {
+                // If it's a directory, push its contents onto the stack
+                let dir_content = match fs::read_dir(&entry_path) {
+                    Ok(v) => v,
+                    Err(e) => match e.raw_os_error().unwrap_or(0) {
+                        24 => {
+                            error!("Too many file handles open, switching to copying.");
+                            for _ in 0..5 {
+                                stack.pop_front();
+                            }
+                            on_discovered(Discovered::FdLimit);
+                            continue;
+                        }
+                        _ => {
+                            let err = format!(
+                                "Couldn't read {:#?} because of error: {e}. Skipping",
+                                entry_path
+                            );
+                            error!("{}", err);
+                            continue;
+                        }
+                    },
+                };
+                stack.push_back(dir_content);
+            } else if entry.file_type().unwrap().is_file() {
+                // If it's a file, add to the list
+                info!("{} {:#?}.", "Discovered".green().bold(), entry.path());
+
+                let meta = FileMeta::from(&entry.metadata().unwrap());
+                on_discovered(Discovered::File(entry, meta));
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn singlethread(
+    src: ReadDir,
+    dest: PathBuf,
+    src_name: OsString,
+    journal: &Journal,
+    resume: &Resume,
+    signatures: &Signatures,
+    backend: &dyn StorageBackend,
+    opts: &CopyOptions,
+) -> (Conclusion, MultiProgress) {
+    let mut file_list: VecDeque<(DirEntry, &OsString, &PathBuf, FileMeta)> = VecDeque::new();
     let mut error_count = 0;
     let mut error_list = Vec::new();
     let mut total_size = FileSize::new();
@@ -470,93 +1226,66 @@ fn singlethread(src: ReadDir, dest: PathBuf, src_name: OsString) -> (Conclusion,
         info!("Increased max files open limit from {} to {}", from, to);
     }
 
-    while let Some(curr_dir) = stack.pop_front() {
-        for entry in curr_dir {
-            let entry = entry.unwrap();
-            let entry_path = entry.path();
+    _discover(src, |discovered| match discovered {
+        Discovered::File(entry, meta) => {
+            total_size.byte += meta.size as usize;
+            file_list.push_front((entry, &src_name, &dest, meta));
+            pb.inc(1);
+        }
+        // We copy the currently discovered files if we reach fd limit
+        Discovered::FdLimit => {
+            let mut progress;
+            let total = total_size.byte;
+            let pb = multi.add(ProgressBar::new(total as u64));
+            pb.set_style(
+                ProgressStyle::with_template(
+                    "{spinner:.green} [{elapsed_precise}] [{bar:50.cyan/blue}] {bytes}/{total_bytes} ({eta})",
+                )
+                .unwrap()
+                .progress_chars("#>-"),
+            );
 
-            if entry.file_type().unwrap().is_dir() {
-                // If it's a directory, push its contents onto the stack
-                let dir_content = match fs::read_dir(&entry_path) {
-                    Ok(v) => v,
-                    Err(e) => match e.raw_os_error().unwrap_or(0) {
-                        // We copy the currently discovered files if we reach fd limit
-                        24 => {
-                            error!("Too many file handles open, switching to copying.");
-                            for _ in 0..5 {
-                                stack.pop_front();
-                            }
-                            let mut progress;
-                            let total = total_size.byte;
-                            let pb = multi.add(ProgressBar::new(total as u64));
-                            pb.set_style(
-                                ProgressStyle::with_template(
-                                    "{spinner:.green} [{elapsed_precise}] [{bar:50.cyan/blue}] {bytes}/{total_bytes} ({eta})",
-                                )
-                                .unwrap()
-                                .progress_chars("#>-"),
-                            );
+            pb.set_position(curr_progress);
 
-                            pb.set_position(curr_progress);
-
-                            let pb_clone = pb.clone();
-                            let t = _pb_update(pb_clone);
-
-                            while let Some(f) = file_list.pop_front() {
-                                let p = f.0.path();
-
-                                progress = f.0.metadata().unwrap().len();
-                                curr_progress += progress;
-                                info!(
-                                    "{} \"{}\" ({})",
-                                    "Copying".green().bold(),
-                                    p.display(),
-                                    FileSize::from(progress).to_string().bold()
-                                );
-
-                                let dest_path = match _copy_file(&f.0, f.1, f.2) {
-                                    Ok(v) => v,
-                                    Err(e) => {
-                                        let err = format!(
-                                            "Couldn't copy {:#?} because of error: {e}. Skipping\n",
-                                            p
-                                        );
-                                        error!("{}", err);
-                                        error_count += 1;
-                                        error_list.push(err);
-                                        continue;
-                                    }
-                                };
-                                path_list.push((p, dest_path));
-                                pb.inc(progress);
-                            }
-                            pb.finish();
-                            multi.remove(&pb);
-                            t.join().unwrap();
+            let pb_clone = pb.clone();
+            let t = _pb_update(pb_clone);
+
+            while let Some(f) = file_list.pop_front() {
+                let p = f.0.path();
+
+                progress = f.3.size;
+                curr_progress += progress;
+                info!(
+                    "{} \"{}\" ({})",
+                    "Copying".green().bold(),
+                    p.display(),
+                    FileSize::from(progress).to_string().bold()
+                );
 
-                            continue;
+                let dest_path =
+                    match _resume_or_copy(&p, f.1, f.2, resume, signatures, backend, opts) {
+                        Ok((v, false)) => v,
+                        Ok((v, true)) => {
+                            journal.copied(&p, &v).unwrap();
+                            v
                         }
-                        _ => {
-                            let err = format!(
-                                "Couldn't read {:#?} because of error: {e}. Skipping",
-                                entry_path
-                            );
+                        Err(e) => {
+                            let err =
+                                format!("Couldn't copy {:#?} because of error: {e}. Skipping\n", p);
                             error!("{}", err);
+                            error_count += 1;
+                            error_list.push(err);
                             continue;
                         }
-                    },
-                };
-                stack.push_back(dir_content);
-            } else if entry.file_type().unwrap().is_file() {
-                // If it's a file, add to the list
-                info!("{} {:#?}.", "Discovered".green().bold(), entry.path());
-
-                total_size.byte += entry.metadata().unwrap().len() as usize;
-                file_list.push_front((entry, &src_name, &dest));
-                pb.inc(1);
+                    };
+                path_list.push((p, dest_path, f.3));
+                pb.inc(progress);
             }
+            pb.finish();
+            multi.remove(&pb);
+            t.join().unwrap();
         }
-    }
+    });
 
     pb.finish();
     t.join().unwrap();
@@ -583,7 +1312,7 @@ fn singlethread(src: ReadDir, dest: PathBuf, src_name: OsString) -> (Conclusion,
     while let Some(f) = file_list.pop_front() {
         let p = f.0.path();
 
-        progress = f.0.metadata().unwrap().len();
+        progress = f.3.size;
         info!(
             "{} \"{}\" ({})",
             "Copying".green().bold(),
@@ -591,8 +1320,12 @@ fn singlethread(src: ReadDir, dest: PathBuf, src_name: OsString) -> (Conclusion,
             FileSize::from(progress).to_string().bold()
         );
 
-        let dest_path = match _copy_file(&f.0, f.1, f.2) {
-            Ok(v) => v,
+        let dest_path = match _resume_or_copy(&p, f.1, f.2, resume, signatures, backend, opts) {
+            Ok((v, false)) => v,
+            Ok((v, true)) => {
+                journal.copied(&p, &v).unwrap();
+                v
+            }
             Err(e) => {
                 let err = format!("Couldn't copy {:#?} because of error: {e}. Skipping\n", p);
                 error!("{}", err);
@@ -601,7 +1334,7 @@ fn singlethread(src: ReadDir, dest: PathBuf, src_name: OsString) -> (Conclusion,
                 continue;
             }
         };
-        path_list.push((p, dest_path));
+        path_list.push((p, dest_path, f.3));
         pb.inc(progress);
     }
 
@@ -610,7 +1343,7 @@ fn singlethread(src: ReadDir, dest: PathBuf, src_name: OsString) -> (Conclusion,
     t.join().unwrap();
 
     total_size.update();
-    return (
+    (
         Conclusion {
             total_count,
             error_count,
@@ -619,7 +1352,58 @@ fn singlethread(src: ReadDir, dest: PathBuf, src_name: OsString) -> (Conclusion,
             path_list,
         },
         multi,
-    );
+    )
+}
+
+/// Copies the files that were given as sources into the root of `dest`.
+fn _copy_files(
+    files: Vec<(&PathBuf, FileMeta)>,
+    dest: &Path,
+    journal: &Journal,
+    resume: &Resume,
+    signatures: &Signatures,
+    backend: &dyn StorageBackend,
+    opts: &CopyOptions,
+) -> (Conclusion, MultiProgress) {
+    let mut conclusion = Conclusion::new();
+
+    let multi = MultiProgress::new();
+    multi.set_move_cursor(true);
+
+    let logger = colog::default_builder().build();
+    let _ = LogWrapper::new(multi.clone(), logger).try_init();
+
+    for (path, meta) in files {
+        info!(
+            "{} \"{}\" ({})",
+            "Copying".green().bold(),
+            path.display(),
+            FileSize::from(meta.size).to_string().bold()
+        );
+        conclusion.total_count += 1;
+        conclusion.total_size.byte += meta.size as usize;
+
+        let name = path.iter().next_back().unwrap().to_owned();
+        match _resume_or_copy(path, &name, dest, resume, signatures, backend, opts) {
+            Ok((v, copied)) => {
+                if copied {
+                    journal.copied(path, &v).unwrap();
+                }
+                conclusion.path_list.push((path.clone(), v, meta));
+            }
+            Err(e) => {
+                let err = format!(
+                    "Couldn't copy {:#?} because of error: {e}. Skipping\n",
+                    path
+                );
+                error!("{}", err);
+                conclusion.error_count += 1;
+                conclusion.error_list.push(err);
+            }
+        }
+    }
+    conclusion.total_size.update();
+    (conclusion, multi)
 }
 
 fn _pb_update(pb_clone: ProgressBar) -> JoinHandle<()> {
@@ -631,19 +1415,45 @@ fn _pb_update(pb_clone: ProgressBar) -> JoinHandle<()> {
     })
 }
 
-fn multithread(src: ReadDir, dest: PathBuf, src_name: OsString) -> (Conclusion, MultiProgress) {
+#[allow(clippy::too_many_arguments)]
+fn multithread(
+    src: ReadDir,
+    dest: PathBuf,
+    src_name: OsString,
+    journal: &Journal,
+    resume: Arc<Resume>,
+    signatures: Arc<Signatures>,
+    backend: Arc<dyn StorageBackend>,
+    opts: Arc<CopyOptions>,
+) -> (Conclusion, MultiProgress) {
     let mut conclusion = Conclusion::new();
 
-    let multi = _multithread(src, dest, src_name, &mut conclusion);
+    let multi = _multithread(
+        src,
+        dest,
+        src_name,
+        &mut conclusion,
+        journal,
+        resume,
+        signatures,
+        backend,
+        opts,
+    );
 
-    return (conclusion, multi);
+    (conclusion, multi)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn _multithread(
     src: ReadDir,
     dest: PathBuf,
     src_name: OsString,
     conclusion: &mut Conclusion,
+    journal: &Journal,
+    resume: Arc<Resume>,
+    signatures: Arc<Signatures>,
+    backend: Arc<dyn StorageBackend>,
+    opts: Arc<CopyOptions>,
 ) -> MultiProgress {
     let mut files_list = Vec::new();
     let mut thread_pool = Vec::new();
@@ -698,7 +1508,9 @@ fn _multithread(
                 conclusion.total_size.byte += x.byte;
                 conclusion.total_size.update();
             }
-            ConclusionFields::PathCouple(x) => conclusion.path_list.push(x),
+            ConclusionFields::PathCouple(x) | ConclusionFields::Resumed(x) => {
+                conclusion.path_list.push(x)
+            }
         }
     }
 
@@ -724,14 +1536,27 @@ fn _multithread(
     while let Some(e) = files_list.pop() {
         let conclusion_clone = conclusion_send.clone();
         let pb_clone = pb.clone();
+        let resume = resume.clone();
+        let signatures = signatures.clone();
+        let backend = backend.clone();
+        let opts = opts.clone();
         thread_pool.push(std::thread::spawn(move || {
             let p = e.0.path();
-            let progress = e.0.metadata().unwrap().len();
+            let progress = e.3.size;
 
             info!("{} {:#?}", "Copying".green().bold(), p);
 
-            let t = match _copy_file(&e.0, &e.1, &e.2) {
-                Ok(v) => v,
+            let t = match _resume_or_copy(
+                &p,
+                &e.1,
+                &e.2,
+                &resume,
+                &signatures,
+                backend.as_ref(),
+                &opts,
+            ) {
+                Ok((v, true)) => ConclusionFields::PathCouple((p, v, e.3)),
+                Ok((v, false)) => ConclusionFields::Resumed((p, v, e.3)),
                 Err(e) => {
                     let err = format!("Couldn't copy {:#?} because of error: {e}", p);
                     error!("{}", err);
@@ -739,9 +1564,7 @@ fn _multithread(
                     return;
                 }
             };
-            conclusion_clone
-                .send(ConclusionFields::PathCouple((p, t)))
-                .unwrap();
+            conclusion_clone.send(t).unwrap();
             pb_clone.inc(progress);
         }));
     }
@@ -758,7 +1581,11 @@ fn _multithread(
                 conclusion.total_size.byte += x.byte;
                 conclusion.total_size.update();
             }
-            ConclusionFields::PathCouple(x) => conclusion.path_list.push(x),
+            ConclusionFields::PathCouple(x) => {
+                journal.copied(&x.0, &x.1).unwrap();
+                conclusion.path_list.push(x)
+            }
+            ConclusionFields::Resumed(x) => conclusion.path_list.push(x),
         }
     }
 
@@ -775,7 +1602,7 @@ fn _multithread_discover(
     dest: PathBuf,
     src_name: OsString,
     conclusion_chan: Sender<ConclusionFields>,
-    files_list_chan: Sender<(DirEntry, OsString, PathBuf)>,
+    files_list_chan: Sender<(DirEntry, OsString, PathBuf, FileMeta)>,
     pb: ProgressBar,
 ) {
     for f in src {
@@ -813,26 +1640,52 @@ fn _multithread_discover(
 
         if entry.file_type().unwrap().is_file() {
             info!("{} {:#?}", "Discovered".green().bold(), entry.path());
+            let meta = FileMeta::from(&entry.metadata().unwrap());
             conclusion_chan
                 .send(ConclusionFields::FileSize(FileSize::from_bytes(
-                    entry.metadata().unwrap().len() as usize,
+                    meta.size as usize,
                 )))
                 .unwrap();
             conclusion_chan
                 .send(ConclusionFields::TotalCount(1))
                 .unwrap();
             files_list_chan
-                .send((entry, src_name.clone(), dest.clone()))
+                .send((entry, src_name.clone(), dest.clone(), meta))
                 .unwrap();
             pb.inc(1);
         }
     }
 }
 
-fn _copy_file(entry: &DirEntry, src_name: &OsString, dest: &PathBuf) -> io::Result<PathBuf> {
-    // Get the full path of the entry
-    let full_path = entry.path();
+/// Copies `entry` unless an interrupted run already did. Returns the destination path and
+/// whether the file was actually copied.
+fn _resume_or_copy(
+    from: &Path,
+    src_name: &OsString,
+    dest: &Path,
+    resume: &Resume,
+    signatures: &Signatures,
+    backend: &dyn StorageBackend,
+    opts: &CopyOptions,
+) -> io::Result<(PathBuf, bool)> {
+    if let Some(v) = resume.copied(from, &fs::metadata(from)?) {
+        info!("{} {:#?}", "Already copied".green().bold(), from);
+        return Ok((v.clone(), false));
+    }
+    Ok((
+        _copy_file(from, src_name, dest, signatures, backend, opts)?,
+        true,
+    ))
+}
 
+fn _copy_file(
+    full_path: &Path,
+    src_name: &OsString,
+    dest: &Path,
+    signatures: &Signatures,
+    backend: &dyn StorageBackend,
+    opts: &CopyOptions,
+) -> io::Result<PathBuf> {
     // Find the position of `src_name` in the full path
     let mut path = PathBuf::new();
     let mut found_src = false;
@@ -861,10 +1714,108 @@ fn _copy_file(entry: &DirEntry, src_name: &OsString, dest: &PathBuf) -> io::Resu
 
     let mut dest_dir = dest.join(&path);
     dest_dir.pop(); // Pop the last element which is the file name.
-    fs::create_dir_all(&dest_dir)?;
 
-    let file_name = entry.file_name();
+    let file_name = full_path.file_name().unwrap_or_default();
     let dest_path = dest_dir.join(file_name);
-    fs::copy(&full_path, &dest_path)?;
+    if opts.delta && dest_path.is_file() && fs::metadata(full_path)?.len() >= delta::MIN_SIZE {
+        let written = delta::update(
+            full_path,
+            &dest_path,
+            signatures.get(full_path),
+            &storage::Local::new(opts),
+        )?;
+        info!(
+            "{} {:#?} ({} written)",
+            "Updated".green().bold(),
+            dest_path,
+            FileSize::from(written)
+        );
+    } else {
+        backend.put_file(full_path, &dest_path)?;
+    }
     Ok(dest_path)
 }
+
+/// Size of the buffer files are read into while hashing them.
+const HASH_BUF_SIZE: usize = 1024 * 1024;
+
+/// Returns the hex encoded SHA-256 hash of everything read from `reader`.
+fn _hash_reader(reader: &mut (impl Read + ?Sized)) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; HASH_BUF_SIZE];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        ratelimit::throttle(n);
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Hashes everything read through it.
+struct HashingReader<R: Read> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    fn finish(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Returns the hex encoded SHA-256 hash of the file at `path`.
+fn _hash_file(path: &Path) -> io::Result<String> {
+    _hash_reader(&mut File::open(path)?)
+}
+
+/// Suffix of the temporary files copies are written to before being renamed into place.
+const TEMP_SUFFIX: &str = ".hardcpy-tmp";
+
+/// Returns the temporary path `to` is written to while it's being copied.
+fn _temp_path(to: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(to.file_name().unwrap_or_default());
+    name.push(TEMP_SUFFIX);
+    to.with_file_name(name)
+}
+
+/// Removes temporary files left behind by interrupted copies under `dir`. Returns how many
+/// were removed.
+fn _remove_temp_files(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    let entries = match fs::read_dir(dir) {
+        Ok(v) => v,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            removed += _remove_temp_files(&entry.path())?;
+        } else if file_type.is_file() && entry.file_name().to_string_lossy().ends_with(TEMP_SUFFIX)
+        {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
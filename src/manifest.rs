@@ -0,0 +1,159 @@
+//! The manifest written into the destination root after every run, so a backup can be
+//! checked on any machine with `sha256sum -c`, even without hardcpy or `backups.db`.
+//!
+//! It's named after the source directory, e.g. `docs.sha256` for a backup of `docs`, or after
+//! the first one for backups of several sources, which get a `# source:` line each. Paths in
+//! it are relative to the destination root. Lines starting with `#` are ignored by
+//! `sha256sum` and carry the metadata of the backup and the size of every file:
+//!
+//! ```text
+//! # hardcpy manifest 1
+//! # backup: 16354831419357049950
+//! # source: /home/user/docs
+//! # created: 2024-10-18T14:08:00+02:00
+//! # host: workstation
+//! # files: 1
+//! # bytes: 6
+//! # size 6 docs/notes.txt
+//! 5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03  docs/notes.txt
+//! ```
+//!
+//! Paths containing a backslash or a newline are escaped the way `sha256sum` does it, by
+//! prefixing the line with a backslash.
+
+use crate::{_load_files, _load_sources, _relative, _temp_path, BackupEntry};
+use rusqlite::Connection;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Version of the manifest format.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Returns the path of the manifest of `backup`.
+pub fn path(backup: &BackupEntry) -> PathBuf {
+    let mut name = backup
+        .from
+        .iter()
+        .next_back()
+        .unwrap_or_default()
+        .to_owned();
+    name.push(".sha256");
+    backup.to.join(name)
+}
+
+/// Escapes `path` like `sha256sum` does. Returns whether the line has to be prefixed with a
+/// backslash.
+fn _escape(path: &Path) -> (bool, String) {
+    let path = path.display().to_string();
+    if !path.contains(['\\', '\n']) {
+        return (false, path);
+    }
+    (true, path.replace('\\', "\\\\").replace('\n', "\\n"))
+}
+
+/// Writes the manifest of `backup` from the files tracked in the catalog, replacing the
+/// previous one atomically. Returns the path of the manifest.
+pub fn write(conn: &Connection, backup: &BackupEntry) -> io::Result<PathBuf> {
+    let files = _load_files(conn, backup.id).map_err(io::Error::other)?;
+    let path = path(backup);
+    let temp = _temp_path(&path);
+
+    let mut out = BufWriter::new(File::create(&temp)?);
+    writeln!(out, "# hardcpy manifest {}", MANIFEST_VERSION)?;
+    writeln!(out, "# backup: {}", backup.id)?;
+    for source in _load_sources(conn, backup).map_err(io::Error::other)? {
+        writeln!(out, "# source: {}", source.display())?;
+    }
+    writeln!(out, "# created: {}", chrono::Local::now().to_rfc3339())?;
+    writeln!(
+        out,
+        "# host: {}",
+        gethostname::gethostname().to_string_lossy()
+    )?;
+    writeln!(out, "# files: {}", files.len())?;
+    writeln!(
+        out,
+        "# bytes: {}",
+        files.iter().filter_map(|v| v.size).sum::<u64>()
+    )?;
+    for entry in &files {
+        let (escaped, name) = _escape(&_relative(&entry.to, &backup.to));
+        if let Some(size) = entry.size {
+            writeln!(out, "# size {} {}", size, name)?;
+        }
+        let prefix = if escaped { "\\" } else { "" };
+        writeln!(out, "{}{}  {}", prefix, entry.sha256, name)?;
+    }
+
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&temp, &path)?;
+    Ok(path)
+}
+
+/// Reverses [`_escape`].
+fn _unescape(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('\\')) => {
+                out.push('\\');
+                chars.next();
+            }
+            ('\\', Some('n')) => {
+                out.push('\n');
+                chars.next();
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn _is_sha256(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|v| v.is_ascii_hexdigit())
+}
+
+/// Parses a checksum file in the format of `sha256sum` (which hardcpy manifests use) or in
+/// the BSD format of `sha256sum --tag`, e.g. `SHA256 (docs/notes.txt) = 5891b5b5...`.
+/// Returns every listed path with its lowercase hash, or the number and content of the first
+/// line that couldn't be parsed.
+pub fn parse(text: &str) -> Result<Vec<(PathBuf, String)>, String> {
+    let mut entries = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (escaped, line) = match line.strip_prefix('\\') {
+            Some(v) => (true, v),
+            None => (false, line),
+        };
+
+        let parsed = if let Some(rest) = line.strip_prefix("SHA256 (") {
+            rest.rsplit_once(") = ")
+                .map(|(path, hash)| (path, hash.trim()))
+        } else {
+            line.split_once(' ').and_then(|(hash, rest)| {
+                // Text mode uses a second space, binary mode a `*` before the path.
+                rest.strip_prefix(' ')
+                    .or_else(|| rest.strip_prefix('*'))
+                    .map(|path| (path, hash))
+            })
+        };
+        match parsed {
+            Some((path, hash)) if _is_sha256(hash) && !path.is_empty() => {
+                let path = if escaped {
+                    _unescape(path)
+                } else {
+                    path.to_string()
+                };
+                entries.push((PathBuf::from(path), hash.to_ascii_lowercase()));
+            }
+            _ => return Err(format!("line {}: \"{}\"", i + 1, line)),
+        }
+    }
+    Ok(entries)
+}
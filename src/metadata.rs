@@ -0,0 +1,96 @@
+use std::fmt;
+use std::fs::{FileType, Metadata};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The type of a tracked entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EntryKind {
+    #[default]
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+impl EntryKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntryKind::File => "file",
+            EntryKind::Dir => "dir",
+            EntryKind::Symlink => "symlink",
+            EntryKind::Other => "other",
+        }
+    }
+}
+
+impl From<FileType> for EntryKind {
+    fn from(value: FileType) -> Self {
+        if value.is_symlink() {
+            EntryKind::Symlink
+        } else if value.is_dir() {
+            EntryKind::Dir
+        } else if value.is_file() {
+            EntryKind::File
+        } else {
+            EntryKind::Other
+        }
+    }
+}
+
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Metadata of a source entry, recorded in the catalog when it's discovered.
+///
+/// Times are in nanoseconds since the Unix epoch. `ctime`, `mode` and `inode` are only
+/// available on Unix and are `None` elsewhere.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FileMeta {
+    pub size: u64,
+    pub mtime: i64,
+    pub ctime: Option<i64>,
+    pub mode: Option<u32>,
+    pub inode: Option<u64>,
+    pub kind: EntryKind,
+}
+
+impl From<&Metadata> for FileMeta {
+    #[cfg(unix)]
+    fn from(value: &Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+
+        Self {
+            size: value.len(),
+            mtime: mtime_ns(value),
+            ctime: Some(value.ctime() * 1_000_000_000 + value.ctime_nsec()),
+            mode: Some(value.mode()),
+            inode: Some(value.ino()),
+            kind: value.file_type().into(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn from(value: &Metadata) -> Self {
+        Self {
+            size: value.len(),
+            mtime: mtime_ns(value),
+            ctime: None,
+            mode: None,
+            inode: None,
+            kind: value.file_type().into(),
+        }
+    }
+}
+
+pub fn mtime_ns(meta: &Metadata) -> i64 {
+    meta.modified().map(time_ns).unwrap_or(0)
+}
+
+fn time_ns(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as i64)
+        .unwrap_or(0)
+}
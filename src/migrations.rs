@@ -0,0 +1,201 @@
+use rusqlite::Connection;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Schema migrations of `backups.db`, in order. The schema version stored in
+/// `PRAGMA user_version` is the number of migrations applied so far.
+///
+/// Never edit a migration once it has been released, add a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: The initial schema. Databases created before versioning already have these
+    // tables, which is why they are created only if they don't exist.
+    "CREATE TABLE IF NOT EXISTS Backups (
+        id INTEGER PRIMARY KEY,
+        source TEXT NOT NULL,
+        dest TEXT NOT NULL,
+        compression TEXT
+    );
+    CREATE TABLE IF NOT EXISTS Files (
+        backup_id INTEGER NOT NULL,
+        source TEXT NOT NULL,
+        dest TEXT NOT NULL,
+        sha256 TEXT NOT NULL,
+        PRIMARY KEY (source, dest)
+    );
+    CREATE TABLE IF NOT EXISTS Journal (
+        backup_id INTEGER NOT NULL,
+        source TEXT NOT NULL,
+        dest TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        mtime INTEGER NOT NULL,
+        verified INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (backup_id, source)
+    );",
+    // 2: Key files by the backup they belong to so overlapping backups don't overwrite each
+    // other's rows, and delete them along with their backup. Orphaned rows are kept, `gc`
+    // cleans them up.
+    "CREATE TABLE Files_new (
+        backup_id INTEGER NOT NULL REFERENCES Backups (id) ON DELETE CASCADE,
+        source TEXT NOT NULL,
+        dest TEXT NOT NULL,
+        sha256 TEXT NOT NULL,
+        PRIMARY KEY (backup_id, source)
+    );
+    INSERT OR REPLACE INTO Files_new (backup_id, source, dest, sha256)
+        SELECT backup_id, source, dest, sha256 FROM Files;
+    DROP TABLE Files;
+    ALTER TABLE Files_new RENAME TO Files;
+    CREATE INDEX Files_backup_id ON Files (backup_id);
+
+    CREATE TABLE Journal_new (
+        backup_id INTEGER NOT NULL REFERENCES Backups (id) ON DELETE CASCADE,
+        source TEXT NOT NULL,
+        dest TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        mtime INTEGER NOT NULL,
+        verified INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (backup_id, source)
+    );
+    INSERT INTO Journal_new SELECT * FROM Journal;
+    DROP TABLE Journal;
+    ALTER TABLE Journal_new RENAME TO Journal;
+    CREATE INDEX Journal_backup_id ON Journal (backup_id);",
+    // 3: Metadata of the source entries, so they don't have to be looked up on disk again.
+    // Times are in nanoseconds since the Unix epoch. Rows from older versions have them unset.
+    "ALTER TABLE Files ADD COLUMN size INTEGER;
+    ALTER TABLE Files ADD COLUMN mtime INTEGER;
+    ALTER TABLE Files ADD COLUMN ctime INTEGER;
+    ALTER TABLE Files ADD COLUMN mode INTEGER;
+    ALTER TABLE Files ADD COLUMN inode INTEGER;
+    ALTER TABLE Files ADD COLUMN kind TEXT;",
+    // 4: History of every run of a backup. Times are in milliseconds since the Unix epoch,
+    // `finished` is unset while the run is going or if it was interrupted.
+    "CREATE TABLE Runs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        backup_id INTEGER NOT NULL REFERENCES Backups (id) ON DELETE CASCADE,
+        started INTEGER NOT NULL,
+        finished INTEGER,
+        duration_ms INTEGER,
+        bytes INTEGER NOT NULL DEFAULT 0,
+        file_count INTEGER NOT NULL DEFAULT 0,
+        copied_count INTEGER NOT NULL DEFAULT 0,
+        error_count INTEGER NOT NULL DEFAULT 0,
+        errors TEXT,
+        host TEXT NOT NULL
+    );
+    CREATE INDEX Runs_backup_id ON Runs (backup_id);",
+    // 5: Location of each file of backups stored as archives. `offset` is the position of
+    // the file's header in the uncompressed archive stream.
+    "ALTER TABLE Files ADD COLUMN archive TEXT;
+    ALTER TABLE Files ADD COLUMN offset INTEGER;",
+    // 6: Block checksums of large files for delta updates. `hashes` holds the SHA-256 hashes
+    // of all blocks concatenated and `weak` their rolling checksums, as little endian u64s.
+    // `dest_size` and `dest_mtime` are set once the destination was verified.
+    "CREATE TABLE Blocks (
+        backup_id INTEGER NOT NULL REFERENCES Backups (id) ON DELETE CASCADE,
+        source TEXT NOT NULL,
+        block_size INTEGER NOT NULL,
+        hashes BLOB NOT NULL,
+        weak BLOB NOT NULL,
+        dest_size INTEGER,
+        dest_mtime INTEGER,
+        PRIMARY KEY (backup_id, source)
+    );",
+    // 7: Command that reaches the peer of backups sent with --remote.
+    "ALTER TABLE Backups ADD COLUMN remote TEXT;",
+    // 8: Every source of backups that cover more than one directory. `Backups.source` holds
+    // the first of them.
+    "CREATE TABLE Sources (
+        backup_id INTEGER NOT NULL REFERENCES Backups (id) ON DELETE CASCADE,
+        source TEXT NOT NULL,
+        PRIMARY KEY (backup_id, source)
+    );",
+];
+
+/// The schema version this binary works with.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+#[derive(Debug)]
+pub enum MigrationError {
+    /// The database was written by a newer version of hardcpy.
+    TooNew {
+        found: u32,
+    },
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::TooNew { found } => write!(
+                f,
+                "The database uses schema version {} but this version of hardcpy only supports up to {}. Please update hardcpy.",
+                found, SCHEMA_VERSION
+            ),
+            MigrationError::Sqlite(e) => write!(f, "Couldn't migrate the database: {}", e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for MigrationError {
+    fn from(value: rusqlite::Error) -> Self {
+        MigrationError::Sqlite(value)
+    }
+}
+
+pub fn version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.query_row("PRAGMA user_version", (), |row| row.get(0))
+}
+
+/// Upgrades the database to [`SCHEMA_VERSION`] and enables foreign key enforcement on `conn`.
+///
+/// If `db_path` is given and the database has any data, a copy of it is saved next to it
+/// before anything is changed. Returns the path of that copy if one was made.
+pub fn migrate(
+    conn: &Connection,
+    db_path: Option<&Path>,
+) -> Result<Option<PathBuf>, MigrationError> {
+    // Tables are rebuilt by some migrations, which foreign keys would get in the way of.
+    conn.pragma_update(None, "foreign_keys", false)?;
+    let backup = _migrate(conn, db_path)?;
+    conn.pragma_update(None, "foreign_keys", true)?;
+    Ok(backup)
+}
+
+fn _migrate(conn: &Connection, db_path: Option<&Path>) -> Result<Option<PathBuf>, MigrationError> {
+    let current = version(conn)?;
+    if current > SCHEMA_VERSION {
+        return Err(MigrationError::TooNew { found: current });
+    }
+    if current == SCHEMA_VERSION {
+        return Ok(None);
+    }
+
+    let mut backup = None;
+    if let Some(db_path) = db_path {
+        if current > 0 || _has_tables(conn)? {
+            let path = PathBuf::from(format!("{}.v{}.bak", db_path.display(), current));
+            if !path.exists() {
+                conn.execute("VACUUM INTO ?1", [path.display().to_string()])?;
+            }
+            backup = Some(path);
+        }
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i as u32 + 1)?;
+        tx.commit()?;
+    }
+    Ok(backup)
+}
+
+fn _has_tables(conn: &Connection) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table'",
+        (),
+        |row| row.get::<usize, i64>(0),
+    )
+    .map(|v| v > 0)
+}
@@ -0,0 +1,111 @@
+//! `--mirror` removes the copies of files that were deleted from the source, so a backup that
+//! is run again keeps mirroring its source instead of growing forever.
+//!
+//! A source that is missing most of its files is more likely an unmounted drive than a
+//! cleanup, so nothing is removed if more than `--max-delete` percent of the tracked files
+//! would go.
+
+use crate::storage::StorageBackend;
+use crate::{_load_backup, _load_files, _load_sources, _relative, FileEntry};
+use colored::Colorize;
+use log::info;
+use rusqlite::Connection;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Share of the tracked files, in percent, that may be removed in one run by default.
+pub const DEFAULT_MAX_DELETE: u32 = 50;
+
+/// Whether something exists at `path`. Errors other than it not existing count as it
+/// existing, so nothing is removed because of them.
+fn _exists(path: &Path) -> bool {
+    !fs::symlink_metadata(path).is_err_and(|e| e.kind() == io::ErrorKind::NotFound)
+}
+
+/// Returns the files tracked by the backup with `id` whose source doesn't exist anymore, and
+/// how many files it tracks in total.
+///
+/// Sources are recorded as they were given, so relative ones only resolve from the directory
+/// the backup was made in. A file only counts as deleted if the source directory it was
+/// backed up from resolves, or for sources that are single files the directory holding them.
+fn _stale(conn: &Connection, id: u64) -> rusqlite::Result<(Vec<FileEntry>, usize)> {
+    let sources = match _load_backup(conn, id)? {
+        Some(backup) => _load_sources(conn, &backup)?,
+        None => Vec::new(),
+    };
+    let files = _load_files(conn, id)?;
+    let total = files.len();
+    let stale = files
+        .into_iter()
+        .filter(|v| {
+            let root = sources.iter().find(|source| v.from.starts_with(source));
+            let root = match root {
+                Some(source) if *source == v.from => match source.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir,
+                    _ => Path::new("."),
+                },
+                Some(source) => source,
+                None => return false,
+            };
+            _exists(root) && !_exists(&v.from)
+        })
+        .collect();
+    Ok((stale, total))
+}
+
+/// Removes the copies of the files tracked by the backup with `id` from `backend` if they were
+/// deleted from the source, along with their catalog entries and the directories left empty
+/// below `root`.
+/// Returns the removed destination paths, or an error without removing anything if more than
+/// `max_percent` percent of the tracked files would be removed.
+pub fn remove_stale(
+    conn: &Connection,
+    id: u64,
+    backend: &dyn StorageBackend,
+    root: &Path,
+    max_percent: u32,
+) -> io::Result<Vec<PathBuf>> {
+    let (stale, total) = _stale(conn, id).map_err(io::Error::other)?;
+    if stale.is_empty() {
+        return Ok(Vec::new());
+    }
+    if stale.len() * 100 > total * max_percent as usize {
+        return Err(io::Error::other(format!(
+            "{} of {} tracked files are missing from the source, which is more than {}%. \
+            Nothing was removed, raise --max-delete if that's expected",
+            stale.len(),
+            total,
+            max_percent
+        )));
+    }
+
+    let mut removed = Vec::with_capacity(stale.len());
+    for entry in stale {
+        match backend.delete(&entry.to) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        conn.execute(
+            "DELETE FROM Files WHERE backup_id = ?1 AND source = ?2",
+            (id as i64, entry.from.display().to_string()),
+        )
+        .map_err(io::Error::other)?;
+        conn.execute(
+            "DELETE FROM Blocks WHERE backup_id = ?1 AND source = ?2",
+            (id as i64, entry.from.display().to_string()),
+        )
+        .map_err(io::Error::other)?;
+        info!("{} \"{}\"", "Removed".red().bold(), entry.to.display());
+
+        // The root itself is kept.
+        if let Some(dir) = entry.to.parent() {
+            if let Some(top) = _relative(dir, root).iter().next() {
+                backend.remove_empty_dirs(dir, &root.join(top));
+            }
+        }
+        removed.push(entry.to);
+    }
+    Ok(removed)
+}
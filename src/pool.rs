@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex, PoisonError};
+use std::thread;
+
+/// Returns the number of jobs to run when none was given: one per core if `multithread` is
+/// set, otherwise one.
+pub fn default_jobs(multithread: bool) -> usize {
+    match multithread {
+        true => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        false => 1,
+    }
+}
+
+/// Runs `work` on every item on up to `jobs` threads. `on_done` is called on the calling
+/// thread with each item and its result, in the order of `items`, so it can write to the
+/// catalog without any locking. Workers stay at most `jobs * 2` items ahead of `on_done`, so
+/// a slow item doesn't pile up the results after it.
+pub fn ordered<T, R>(
+    jobs: usize,
+    items: &[T],
+    work: impl Fn(&T) -> R + Sync,
+    mut on_done: impl FnMut(&T, R),
+) where
+    T: Sync,
+    R: Send,
+{
+    if jobs <= 1 || items.len() <= 1 {
+        for item in items {
+            on_done(item, work(item));
+        }
+        return;
+    }
+
+    let window = jobs * 2;
+    let next = AtomicUsize::new(0);
+    // How many results were handled, workers wait on it before starting an item too far ahead.
+    let handled = (Mutex::new(0usize), Condvar::new());
+    let (tx, rx) = mpsc::sync_channel(window);
+    thread::scope(|s| {
+        for _ in 0..jobs.min(items.len()) {
+            let tx = tx.clone();
+            let (next, handled, work) = (&next, &handled, &work);
+            s.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= items.len() {
+                    break;
+                }
+                drop(
+                    handled
+                        .1
+                        .wait_while(handled.0.lock().unwrap(), |v| i >= v.saturating_add(window))
+                        .unwrap(),
+                );
+                if tx.send((i, work(&items[i]))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+
+        // Results arrive in any order, hold them back until every earlier one was handled.
+        let _release = Release(&handled);
+        let mut pending = BTreeMap::new();
+        let mut expected = 0;
+        for (i, result) in rx {
+            pending.insert(i, result);
+            while let Some(result) = pending.remove(&expected) {
+                on_done(&items[expected], result);
+                expected += 1;
+            }
+            *handled.0.lock().unwrap() = expected;
+            handled.1.notify_all();
+        }
+    });
+}
+
+/// Lets every waiting worker go once results stop being handled, including when `on_done`
+/// panics, so the scope can still join them. They exit as soon as they can't send anymore.
+struct Release<'a>(&'a (Mutex<usize>, Condvar));
+
+impl Drop for Release<'_> {
+    fn drop(&mut self) {
+        let (handled, cvar) = self.0;
+        *handled.lock().unwrap_or_else(PoisonError::into_inner) = usize::MAX;
+        cvar.notify_all();
+    }
+}
@@ -0,0 +1,132 @@
+use chrono::Timelike;
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How far ahead of the limit reads may run, so short bursts don't stall on every read.
+const BURST: Duration = Duration::from_millis(250);
+
+/// The limiter every copy, hash and verify read goes through. Unset if there is no limit.
+static LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+
+/// Times of day during which a limit applies, as minutes since midnight. A window may wrap
+/// around midnight, e.g. `22:00-06:00`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    windows: Vec<(u32, u32)>,
+}
+
+impl Schedule {
+    /// Whether `minute` (minutes since midnight) falls in one of the windows.
+    pub fn contains(&self, minute: u32) -> bool {
+        self.windows.iter().any(|&(start, end)| match start <= end {
+            true => (start..end).contains(&minute),
+            false => minute >= start || minute < end,
+        })
+    }
+}
+
+fn _parse_time(value: &str) -> Result<u32, String> {
+    let (h, m) = value
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| format!("invalid time \"{value}\", expected HH:MM"))?;
+    match (h.parse::<u32>(), m.parse::<u32>()) {
+        (Ok(h), Ok(m)) if h < 24 && m < 60 => Ok(h * 60 + m),
+        (Ok(24), Ok(0)) => Ok(24 * 60),
+        _ => Err(format!("invalid time \"{value}\", expected HH:MM")),
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    /// Parses comma separated windows such as `08:00-12:00,13:00-18:00`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let windows = s
+            .split(',')
+            .map(|window| {
+                let (start, end) = window
+                    .split_once('-')
+                    .ok_or_else(|| format!("invalid window \"{window}\", expected HH:MM-HH:MM"))?;
+                Ok((_parse_time(start)?, _parse_time(end)?))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self { windows })
+    }
+}
+
+/// A token bucket shared by every thread, limiting I/O to `rate` bytes per second.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: u64,
+    schedule: Option<Schedule>,
+    /// When the bytes consumed so far will have been paid for.
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64, schedule: Option<Schedule>) -> Self {
+        Self {
+            rate: rate.max(1),
+            schedule,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn _active(&self) -> bool {
+        match &self.schedule {
+            Some(schedule) => {
+                let now = chrono::Local::now();
+                schedule.contains(now.hour() * 60 + now.minute())
+            }
+            None => true,
+        }
+    }
+
+    /// Accounts for `bytes` of I/O, sleeping until they fit within the rate.
+    pub fn consume(&self, bytes: usize) {
+        if !self._active() {
+            return;
+        }
+        let wait = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            *next = (*next).max(now) + Duration::from_secs_f64(bytes as f64 / self.rate as f64);
+            next.saturating_duration_since(now + BURST)
+        };
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Installs the limiter used by [`throttle`] for the rest of the process.
+pub fn install(limiter: RateLimiter) {
+    let _ = LIMITER.set(limiter);
+}
+
+/// Whether a limit was installed.
+pub fn is_limited() -> bool {
+    LIMITER.get().is_some()
+}
+
+/// Accounts for `bytes` of I/O against the installed limit, if any.
+pub fn throttle(bytes: usize) {
+    if let Some(limiter) = LIMITER.get() {
+        limiter.consume(bytes);
+    }
+}
+
+/// Throttles everything read through it.
+pub struct Throttled<R: Read>(pub R);
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.0.read(buf)?;
+        throttle(n);
+        Ok(n)
+    }
+}
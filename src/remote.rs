@@ -0,0 +1,526 @@
+//! Backups to a peer reached through a pipe, e.g. `ssh nas hardcpy serve`.
+//!
+//! The peer reads requests from stdin and writes responses to stdout. Every frame starts
+//! with a one byte type and a four byte big endian payload length:
+//!
+//! - `0`: a JSON encoded [`Request`] or [`Response`].
+//! - `1`: a chunk of file data following a `put` request.
+//! - `2`: the end of the file data of a `put` request, with an empty payload.
+//! - `3`: like `2`, but the file couldn't be read to the end and is discarded.
+//!
+//! The client starts with `hello` and waits for its response. After a `put` it sends the
+//! file data and waits for the response holding the hash of what the peer wrote, so a file
+//! is verified as it's sent. Files are written to a temporary file and renamed into place
+//! once complete, like local copies.
+
+use crate::metadata::FileMeta;
+use crate::ratelimit::Throttled;
+use crate::runs;
+use crate::{
+    _backup_id, _discover, _format_duration, _hash_file, _load_files, _pb_update, _record_file,
+    _relative, _temp_path, BackupEntry, CopyOptions, Discovered, FileSize, HashingReader,
+};
+use colored::Colorize;
+use indicatif::{HumanCount, MultiProgress, ProgressBar, ProgressStyle};
+use indicatif_log_bridge::LogWrapper;
+use log::{error, info};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Instant;
+
+/// Version of the protocol, sent in `hello`. Bumped whenever a change would break peers.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Size of the data chunks files are sent in.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Frames bigger than this are rejected, so a corrupt stream can't exhaust memory.
+const MAX_FRAME: u32 = 16 * 1024 * 1024;
+
+const FRAME_MESSAGE: u8 = 0;
+const FRAME_DATA: u8 = 1;
+const FRAME_END: u8 = 2;
+const FRAME_ABORT: u8 = 3;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    Hello {
+        version: u32,
+    },
+    /// Writes the file data that follows to `path`.
+    Put {
+        path: String,
+        fsync: bool,
+    },
+    /// Returns the hash of the file at `path`.
+    Hash {
+        path: String,
+    },
+    Quit,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Response {
+    Ok { sha256: Option<String> },
+    Error { message: String },
+}
+
+fn _write_frame(writer: &mut impl Write, kind: u8, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&[kind])?;
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(payload)
+}
+
+/// Reads a frame into `buf`. Returns its type, or `None` at the end of the stream.
+fn _read_frame(reader: &mut impl Read, buf: &mut Vec<u8>) -> io::Result<Option<u8>> {
+    let mut header = [0; 5];
+    match reader.read_exact(&mut header[..1]) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    reader.read_exact(&mut header[1..])?;
+    let len = u32::from_be_bytes(header[1..].try_into().unwrap());
+    if len > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {len} bytes is too large"),
+        ));
+    }
+    buf.resize(len as usize, 0);
+    reader.read_exact(buf)?;
+    Ok(Some(header[0]))
+}
+
+fn _write_message(writer: &mut impl Write, message: &impl Serialize) -> io::Result<()> {
+    _write_frame(writer, FRAME_MESSAGE, &serde_json::to_vec(message)?)?;
+    writer.flush()
+}
+
+fn _read_message<T: for<'a> Deserialize<'a>>(reader: &mut impl Read) -> io::Result<T> {
+    let mut buf = Vec::new();
+    match _read_frame(reader, &mut buf)? {
+        Some(FRAME_MESSAGE) => Ok(serde_json::from_slice(&buf)?),
+        Some(kind) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected a message, got a frame of type {kind}"),
+        )),
+        None => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the peer closed the connection",
+        )),
+    }
+}
+
+/// A connection to a peer running `hardcpy serve`.
+pub struct Peer {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: BufWriter<Box<dyn Write + Send>>,
+    child: Option<Child>,
+}
+
+impl Peer {
+    /// Connects to a peer over `reader` and `writer`.
+    pub fn new(
+        reader: impl Read + Send + 'static,
+        writer: impl Write + Send + 'static,
+    ) -> io::Result<Self> {
+        let mut peer = Self {
+            reader: BufReader::new(Box::new(reader)),
+            writer: BufWriter::new(Box::new(writer)),
+            child: None,
+        };
+        peer.request(&Request::Hello {
+            version: PROTOCOL_VERSION,
+        })?;
+        Ok(peer)
+    }
+
+    /// Runs `command` through the shell and connects to it over its stdin and stdout.
+    pub fn spawn(command: &str) -> io::Result<Self> {
+        #[cfg(unix)]
+        let mut cmd = Command::new("sh");
+        #[cfg(unix)]
+        cmd.arg("-c");
+        #[cfg(windows)]
+        let mut cmd = Command::new("cmd");
+        #[cfg(windows)]
+        cmd.arg("/C");
+
+        let mut child = cmd
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let mut peer = Self::new(stdout, stdin).map_err(|e| {
+            io::Error::new(e.kind(), format!("Couldn't connect to \"{command}\": {e}"))
+        })?;
+        peer.child = Some(child);
+        Ok(peer)
+    }
+
+    fn _response(&mut self) -> io::Result<Option<String>> {
+        match _read_message(&mut self.reader)? {
+            Response::Ok { sha256 } => Ok(sha256),
+            Response::Error { message } => Err(io::Error::other(message)),
+        }
+    }
+
+    fn request(&mut self, request: &Request) -> io::Result<Option<String>> {
+        _write_message(&mut self.writer, request)?;
+        self._response()
+    }
+
+    /// Sends everything read from `reader` to `path` on the peer. Returns the hash of what
+    /// the peer wrote.
+    pub fn put(&mut self, path: &Path, reader: &mut impl Read, fsync: bool) -> io::Result<String> {
+        _write_message(
+            &mut self.writer,
+            &Request::Put {
+                path: path.display().to_string(),
+                fsync,
+            },
+        )?;
+        let mut buf = vec![0; CHUNK_SIZE];
+        let read = loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(n) => _write_frame(&mut self.writer, FRAME_DATA, &buf[..n])?,
+                Err(e) => break Err(e),
+            }
+        };
+        // The peer has to be told either way to stay in sync.
+        let kind = match read {
+            Ok(()) => FRAME_END,
+            Err(_) => FRAME_ABORT,
+        };
+        _write_frame(&mut self.writer, kind, &[])?;
+        self.writer.flush()?;
+        let response = self._response();
+        read?;
+        response?.ok_or_else(|| io::Error::other("the peer didn't return a hash"))
+    }
+
+    /// Returns the hash of the file at `path` on the peer.
+    pub fn hash(&mut self, path: &Path) -> io::Result<String> {
+        self.request(&Request::Hash {
+            path: path.display().to_string(),
+        })?
+        .ok_or_else(|| io::Error::other("the peer didn't return a hash"))
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        let _ = _write_message(&mut self.writer, &Request::Quit);
+        if let Some(child) = &mut self.child {
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Resolves `path` sent by a client. With a `root`, paths are relative to it and may not
+/// leave it.
+fn _resolve(root: Option<&Path>, path: &str) -> io::Result<PathBuf> {
+    let path = Path::new(path);
+    match root {
+        None => Ok(path.to_path_buf()),
+        Some(_) if path.components().any(|v| v == Component::ParentDir) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{:#?} is outside of the served directory", path),
+        )),
+        Some(root) => Ok(root.join(path.strip_prefix("/").unwrap_or(path))),
+    }
+}
+
+/// Receives the file data following a `put` and writes it to `path` atomically. Returns
+/// the hash of what was written.
+fn _receive(reader: &mut impl Read, path: &Path, fsync: bool) -> io::Result<String> {
+    // The data has to be read to the end even if it can't be written, to stay in sync.
+    let temp = _temp_path(path);
+    let mut file = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| File::create(&temp));
+    let mut hasher = Sha256::new();
+    let mut buf = Vec::new();
+    loop {
+        match _read_frame(reader, &mut buf)? {
+            Some(FRAME_DATA) => {
+                hasher.update(&buf);
+                if let Ok(f) = &mut file {
+                    if let Err(e) = f.write_all(&buf) {
+                        file = Err(e);
+                    }
+                }
+            }
+            Some(FRAME_END) => break,
+            Some(FRAME_ABORT) => {
+                file = Err(io::Error::other("the client couldn't read the file"));
+                break;
+            }
+            _ => {
+                let _ = fs::remove_file(&temp);
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the file data ended unexpectedly",
+                ));
+            }
+        }
+    }
+    let result = file.and_then(|f| {
+        if fsync {
+            f.sync_all()?;
+        }
+        fs::rename(&temp, path)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result.map(|_| format!("{:x}", hasher.finalize()))
+}
+
+/// Reads and discards the file data following a rejected `put`.
+fn _skip_data(reader: &mut impl Read) -> io::Result<()> {
+    let mut buf = Vec::new();
+    loop {
+        match _read_frame(reader, &mut buf)? {
+            Some(FRAME_DATA) => {}
+            Some(FRAME_END | FRAME_ABORT) => return Ok(()),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the file data ended unexpectedly",
+                ))
+            }
+        }
+    }
+}
+
+/// Serves requests read from `reader` until the client quits or disconnects. Paths are
+/// relative to `root` if it's given.
+pub fn serve(reader: impl Read, writer: impl Write, root: Option<&Path>) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    loop {
+        let request = match _read_message::<Request>(&mut reader) {
+            Ok(v) => v,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let result = match request {
+            Request::Hello { version } if version == PROTOCOL_VERSION => Ok(None),
+            Request::Hello { version } => Err(io::Error::other(format!(
+                "the client uses protocol version {version} but the peer uses {PROTOCOL_VERSION}"
+            ))),
+            Request::Put { path, fsync } => {
+                let path = _resolve(root, &path);
+                match path {
+                    Ok(path) => _receive(&mut reader, &path, fsync).map(Some),
+                    Err(e) => _skip_data(&mut reader).and(Err(e)),
+                }
+            }
+            Request::Hash { path } => _resolve(root, &path).and_then(|v| _hash_file(&v)).map(Some),
+            Request::Quit => return Ok(()),
+        };
+        let response = match result {
+            Ok(sha256) => Response::Ok { sha256 },
+            Err(e) => Response::Error {
+                message: e.to_string(),
+            },
+        };
+        _write_message(&mut writer, &response)?;
+    }
+}
+
+/// Returns the id of the backup of `source` into `dest` on the peer run by `command`.
+/// The command is part of the id so it doesn't collide with a local backup to the same path.
+pub fn backup_id(command: &str, source: &Path, dest: &Path) -> u64 {
+    _backup_id(
+        source,
+        Path::new(&format!("{}:{}", command, dest.display())),
+    )
+}
+
+/// Sends the source tree to `dest` on the peer run by `command`. Returns `true` on failure.
+pub fn create(
+    conn: &Connection,
+    opts: &CopyOptions,
+    command: &str,
+    source_str: &Path,
+    dest_str: &Path,
+) -> bool {
+    let mut peer = match Peer::spawn(command) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{} {}", "Error:".red().bold(), e);
+            return true;
+        }
+    };
+    upload(conn, opts, command, &mut peer, source_str, dest_str)
+}
+
+/// Sends the source tree to `dest` through `peer`, recording every file and the hash the
+/// peer computed in the catalog. Returns `true` on failure.
+pub fn upload(
+    conn: &Connection,
+    opts: &CopyOptions,
+    command: &str,
+    peer: &mut Peer,
+    source_str: &Path,
+    dest_str: &Path,
+) -> bool {
+    let source_name = source_str.iter().next_back().unwrap().to_owned();
+    let source = match fs::read_dir(source_str) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("Error: {} (\"{}\")", e, source_str.display());
+            return true;
+        }
+    };
+
+    let id = backup_id(command, source_str, dest_str);
+    conn.execute(
+        "INSERT INTO Backups (id, source, dest, remote) VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT (id) DO UPDATE SET source = excluded.source, dest = excluded.dest,
+        remote = excluded.remote",
+        (
+            id as i64,
+            source_str.display().to_string(),
+            dest_str.display().to_string(),
+            command,
+        ),
+    )
+    .unwrap();
+
+    let multi = MultiProgress::new();
+    let logger = colog::default_builder().build();
+    let _ = LogWrapper::new(multi.clone(), logger).try_init();
+
+    let timer = Instant::now();
+    let run_id = runs::start(conn, id).unwrap();
+
+    let mut files = Vec::new();
+    _discover(source, |discovered| match discovered {
+        Discovered::File(entry, meta) => files.push((entry.path(), meta)),
+        Discovered::FdLimit => error!("Too many file handles open, some files will be missing."),
+    });
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    let total: u64 = files.iter().map(|(_, meta)| meta.size).sum();
+
+    let pb = multi.add(ProgressBar::new(total));
+    pb.set_style(
+        ProgressStyle::with_template(
+            "{spinner:.green} [{elapsed_precise}] [{bar:50.cyan/blue}] {bytes}/{total_bytes} ({eta})",
+        )
+        .unwrap()
+        .progress_chars("#>-"),
+    );
+    let t = _pb_update(pb.clone());
+
+    let tx = conn.unchecked_transaction().unwrap();
+    let mut error_list = Vec::new();
+    let mut sent = 0;
+    for (path, meta) in &files {
+        info!(
+            "{} \"{}\" ({})",
+            "Sending".green().bold(),
+            path.display(),
+            FileSize::from(meta.size).to_string().bold()
+        );
+        let to = dest_str
+            .join(&source_name)
+            .join(_relative(path, source_str));
+        let result = File::open(path).and_then(|file| {
+            let fs_meta = file.metadata()?;
+            let mut reader = HashingReader::new(Throttled(file));
+            let remote = peer.put(&to, &mut reader, opts.fsync)?;
+            let local = reader.finish();
+            if remote != local {
+                return Err(io::Error::other("the peer received different data"));
+            }
+            Ok((local, FileMeta::from(&fs_meta)))
+        });
+        match result {
+            Ok((hash, meta)) => {
+                _record_file(&tx, id, path, &to, &hash, &meta).unwrap();
+                sent += 1;
+            }
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                let err = format!("Lost the connection to the peer: {e}\n");
+                error!("{}", err);
+                error_list.push(err);
+                break;
+            }
+            Err(e) => {
+                let err = format!(
+                    "Couldn't copy {:#?} because of error: {e}. Skipping\n",
+                    path
+                );
+                error!("{}", err);
+                error_list.push(err);
+            }
+        }
+        pb.inc(meta.size);
+    }
+    tx.commit().unwrap();
+    pb.finish();
+    t.join().unwrap();
+    multi.remove(&pb);
+
+    let elapsed = timer.elapsed();
+    runs::finish(
+        conn,
+        run_id,
+        elapsed,
+        total,
+        files.len(),
+        sent as usize,
+        &error_list,
+    )
+    .unwrap();
+
+    println!(
+        "\n{} {} files {}{}{} in {} {}{}{}",
+        "Sent".green().bold(),
+        HumanCount(sent),
+        "(".truecolor(150, 150, 150),
+        FileSize::from(total).to_string().truecolor(150, 150, 150),
+        ")".truecolor(150, 150, 150),
+        _format_duration(elapsed),
+        "(".truecolor(150, 150, 150),
+        error_list.len().to_string().truecolor(150, 150, 150),
+        " errors)".truecolor(150, 150, 150),
+    );
+    !error_list.is_empty()
+}
+
+/// Asks the peer to hash every file of `backup` and compares the hashes with the catalog.
+/// Returns how many files matched and the errors for the rest.
+pub fn verify(conn: &Connection, backup: &BackupEntry, peer: &mut Peer) -> (usize, Vec<String>) {
+    let mut verified = 0;
+    let mut errors = Vec::new();
+    for entry in _load_files(conn, backup.id).unwrap() {
+        info!("{} \"{}\"", "Verifying".green().bold(), entry.to.display());
+        let err = match peer.hash(&entry.to) {
+            Ok(hash) if hash == entry.sha256 => {
+                verified += 1;
+                continue;
+            }
+            Ok(_) => format!("{:#?} doesn't match its hash\n", entry.to),
+            Err(e) => format!("Couldn't verify {:#?}: {e}\n", entry.to),
+        };
+        error!("{}", err);
+        errors.push(err);
+    }
+    (verified, errors)
+}
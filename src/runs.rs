@@ -0,0 +1,103 @@
+use rusqlite::{Connection, Result};
+use std::time::Duration;
+
+/// A single run of a backup, as recorded in the `Runs` table.
+#[derive(Debug)]
+pub struct Run {
+    pub id: i64,
+    pub backup_id: u64,
+    /// Milliseconds since the Unix epoch.
+    pub started: i64,
+    /// Milliseconds since the Unix epoch. `None` if the run never finished.
+    pub finished: Option<i64>,
+    pub duration: Option<Duration>,
+    pub bytes: u64,
+    pub file_count: u64,
+    pub copied_count: u64,
+    pub error_count: u64,
+    pub errors: Vec<String>,
+    pub host: String,
+}
+
+impl Run {
+    pub fn succeeded(&self) -> bool {
+        self.finished.is_some() && self.error_count == 0
+    }
+}
+
+/// Records the start of a run of `backup_id` and returns the id of the run.
+pub fn start(conn: &Connection, backup_id: u64) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO Runs (backup_id, started, host) VALUES (?1, ?2, ?3)",
+        (
+            backup_id as i64,
+            chrono::Utc::now().timestamp_millis(),
+            gethostname::gethostname().to_string_lossy().to_string(),
+        ),
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Records the outcome of the run with `id`. `copied_count` is counted separately from
+/// `errors`, which also holds errors that happened after the files were copied.
+pub fn finish(
+    conn: &Connection,
+    id: i64,
+    duration: Duration,
+    bytes: u64,
+    file_count: usize,
+    copied_count: usize,
+    errors: &[String],
+) -> Result<()> {
+    conn.execute(
+        "UPDATE Runs SET finished = ?2, duration_ms = ?3, bytes = ?4, file_count = ?5,
+        copied_count = ?6, error_count = ?7, errors = ?8 WHERE id = ?1",
+        (
+            id,
+            chrono::Utc::now().timestamp_millis(),
+            duration.as_millis() as i64,
+            bytes as i64,
+            file_count as i64,
+            copied_count as i64,
+            errors.len() as i64,
+            errors
+                .iter()
+                .map(|e| e.trim_end())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        ),
+    )?;
+    Ok(())
+}
+
+/// Returns the runs of `backup_id`, or of every backup if it's `None`, newest first.
+pub fn list(conn: &Connection, backup_id: Option<u64>, limit: usize) -> Result<Vec<Run>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, backup_id, started, finished, duration_ms, bytes, file_count,
+        copied_count, error_count, errors, host FROM Runs
+        WHERE ?1 IS NULL OR backup_id = ?1 ORDER BY started DESC, id DESC LIMIT ?2",
+    )?;
+    let runs = stmt
+        .query_map((backup_id.map(|v| v as i64), limit as i64), |row| {
+            Ok(Run {
+                id: row.get(0)?,
+                backup_id: row.get::<usize, i64>(1)? as u64,
+                started: row.get(2)?,
+                finished: row.get(3)?,
+                duration: row
+                    .get::<usize, Option<i64>>(4)?
+                    .map(|v| Duration::from_millis(v as u64)),
+                bytes: row.get::<usize, i64>(5)? as u64,
+                file_count: row.get::<usize, i64>(6)? as u64,
+                copied_count: row.get::<usize, i64>(7)? as u64,
+                error_count: row.get::<usize, i64>(8)? as u64,
+                errors: row
+                    .get::<usize, Option<String>>(9)?
+                    .map(|v| v.lines().map(str::to_string).collect())
+                    .unwrap_or_default(),
+                host: row.get(10)?,
+            })
+        })?
+        .collect();
+    runs
+}
@@ -0,0 +1,573 @@
+//! Backups to S3 compatible object storage, e.g. `hardcpy create docs s3://bucket/prefix`.
+//!
+//! The endpoint and credentials are read from the `[s3]` section of `config.ini` next to
+//! the catalog, which a `[s3.<bucket>]` section can override for a single bucket:
+//!
+//! ```ini
+//! [s3]
+//! endpoint = http://localhost:9000
+//! region = us-east-1
+//! access_key = minioadmin
+//! secret_key = minioadmin
+//! path_style = true
+//! ```
+//!
+//! Unset values fall back to `AWS_ENDPOINT_URL`, `AWS_REGION`, `AWS_ACCESS_KEY_ID` and
+//! `AWS_SECRET_ACCESS_KEY`, and then to AWS itself in `us-east-1`. Path style addressing
+//! defaults to on for custom endpoints, which is what MinIO and most other implementations
+//! expect.
+//!
+//! Every object carries the SHA-256 hash of the file as `x-amz-meta-sha256`. Files bigger
+//! than a part are sent with a multipart upload. Requests are signed with Signature Version
+//! 4 including the hash of their payload, so the storage rejects data corrupted on the way.
+
+use crate::archive::Format;
+use crate::copy::{CopyMethod, Reflink};
+use crate::ratelimit::Throttled;
+use crate::storage::{Stat, StorageBackend};
+use crate::{_config_dir, _hash_reader, CopyOptions};
+use chrono::{DateTime, Utc};
+use colored::Colorize;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Size of the parts of multipart uploads. Files up to this size are sent in one request.
+const PART_SIZE: u64 = 8 * 1024 * 1024;
+
+/// S3 doesn't allow more parts than this, bigger files use bigger parts.
+const MAX_PARTS: u64 = 10_000;
+
+/// The metadata key holding the hash of the file.
+const SHA256_HEADER: &str = "x-amz-meta-sha256";
+
+/// The hash of an empty payload.
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// An object, or a prefix of objects, in a bucket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Path {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl S3Path {
+    /// Parses `s3://bucket/key`. Returns `None` if `path` isn't an S3 URL.
+    pub fn parse(path: &Path) -> Option<Self> {
+        let rest = path.to_str()?.strip_prefix("s3://")?;
+        let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return None;
+        }
+        Some(Self {
+            bucket: bucket.to_string(),
+            key: key.trim_matches('/').to_string(),
+        })
+    }
+}
+
+impl fmt::Display for S3Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.key)
+    }
+}
+
+/// Where a bucket is and how to sign requests to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// The scheme and host of the service, e.g. `https://s3.eu-central-1.amazonaws.com`.
+    pub endpoint: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Whether the bucket is part of the path rather than of the host name.
+    pub path_style: bool,
+}
+
+impl Config {
+    /// Reads the settings for `bucket` from `config.ini` and the environment.
+    pub fn load(bucket: &str) -> io::Result<Self> {
+        let path = _config_dir().join("config.ini");
+        let ini = match path.exists() {
+            true => ini::Ini::load_from_file(&path).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Couldn't read {:#?}: {e}", path),
+                )
+            })?,
+            false => ini::Ini::new(),
+        };
+        Self::from_ini(&ini, bucket, |name| std::env::var(name).ok())
+    }
+
+    /// Reads the settings for `bucket` from `ini`, falling back to `env` for unset values.
+    pub fn from_ini(
+        ini: &ini::Ini,
+        bucket: &str,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> io::Result<Self> {
+        let bucket_section = format!("s3.{bucket}");
+        let get = |key: &str, var: &str| {
+            ini.get_from(Some(bucket_section.as_str()), key)
+                .or_else(|| ini.get_from(Some("s3"), key))
+                .map(str::to_string)
+                .or_else(|| env(var))
+        };
+        let missing = |key: &str| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No S3 {key} is configured for \"{bucket}\""),
+            )
+        };
+
+        let region = get("region", "AWS_REGION").unwrap_or_else(|| "us-east-1".to_string());
+        let endpoint = get("endpoint", "AWS_ENDPOINT_URL");
+        let path_style = match get("path_style", "") {
+            Some(v) => matches!(v.as_str(), "true" | "yes" | "1"),
+            None => endpoint.is_some(),
+        };
+        Ok(Self {
+            endpoint: endpoint
+                .map(|v| v.trim_end_matches('/').to_string())
+                .unwrap_or_else(|| format!("https://s3.{region}.amazonaws.com")),
+            access_key: get("access_key", "AWS_ACCESS_KEY_ID")
+                .ok_or_else(|| missing("access_key"))?,
+            secret_key: get("secret_key", "AWS_SECRET_ACCESS_KEY")
+                .ok_or_else(|| missing("secret_key"))?,
+            region,
+            path_style,
+        })
+    }
+}
+
+/// Percent encodes `value` as SigV4 requires. `/` is kept if `path` is set.
+fn _uri_encode(value: &str, path: bool) -> String {
+    let mut out = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            b'/' if path => out.push('/'),
+            _ => out.push_str(&format!("%{b:02X}")),
+        }
+    }
+    out
+}
+
+fn _hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn _hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|v| format!("{v:02x}")).collect()
+}
+
+/// Returns the `Authorization` header for a request. `query` must be encoded and sorted
+/// and `headers` lowercase and sorted, and both include everything that's sent.
+pub fn authorization(
+    config: &Config,
+    method: &str,
+    uri: &str,
+    query: &str,
+    headers: &[(String, String)],
+    payload_hash: &str,
+    time: DateTime<Utc>,
+) -> String {
+    let date = time.format("%Y%m%d").to_string();
+    let signed_headers = headers
+        .iter()
+        .map(|(k, _)| k.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(k, v)| format!("{k}:{}\n", v.trim()))
+        .collect();
+    let canonical_request =
+        format!("{method}\n{uri}\n{query}\n{canonical_headers}\n{signed_headers}\n{payload_hash}");
+
+    let scope = format!("{date}/{}/s3/aws4_request", config.region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{scope}\n{:x}",
+        time.format("%Y%m%dT%H%M%SZ"),
+        Sha256::digest(canonical_request.as_bytes())
+    );
+    let key = _hmac(format!("AWS4{}", config.secret_key).as_bytes(), &date);
+    let key = _hmac(&key, &config.region);
+    let key = _hmac(&key, "s3");
+    let key = _hmac(&key, "aws4_request");
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={}",
+        config.access_key,
+        _hex(&_hmac(&key, &string_to_sign))
+    )
+}
+
+/// Turns a failed request into an error, including the code S3 returned.
+fn _error(e: ureq::Error) -> io::Error {
+    match e {
+        ureq::Error::Status(status, response) => {
+            let body = response.into_string().unwrap_or_default();
+            let kind = match status {
+                404 => io::ErrorKind::NotFound,
+                401 | 403 => io::ErrorKind::PermissionDenied,
+                _ => io::ErrorKind::Other,
+            };
+            let message = match (_xml_value(&body, "Code"), _xml_value(&body, "Message")) {
+                (Some(code), Some(message)) => format!("{code}: {message}"),
+                (Some(code), None) => code.to_string(),
+                _ => format!("the server responded with status {status}"),
+            };
+            io::Error::new(kind, message)
+        }
+        ureq::Error::Transport(e) => io::Error::other(e.to_string()),
+    }
+}
+
+/// Replaces the entities S3 uses in XML text.
+fn _xml_unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Returns the text of the first `tag` element in `xml`. S3 responses are simple enough
+/// to not need a parser.
+fn _xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{tag}>"))?;
+    Some(&xml[start..end])
+}
+
+/// A connection to an S3 compatible service.
+pub struct Client {
+    config: Config,
+    agent: ureq::Agent,
+}
+
+impl Client {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            agent: ureq::AgentBuilder::new().build(),
+        }
+    }
+
+    /// Sends a signed request for `object`. `query` holds unencoded pairs.
+    fn _request(
+        &self,
+        method: &str,
+        object: &S3Path,
+        query: &[(&str, &str)],
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<ureq::Response> {
+        let (scheme, authority) = self
+            .config
+            .endpoint
+            .split_once("://")
+            .unwrap_or(("https", &self.config.endpoint));
+        let (host, uri) = match self.config.path_style {
+            true => (
+                authority.to_string(),
+                format!("/{}/{}", object.bucket, _uri_encode(&object.key, true)),
+            ),
+            false => (
+                format!("{}.{authority}", object.bucket),
+                format!("/{}", _uri_encode(&object.key, true)),
+            ),
+        };
+        let mut query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", _uri_encode(k, false), _uri_encode(v, false)))
+            .collect::<Vec<_>>();
+        query.sort();
+        let query = query.join("&");
+
+        let payload_hash = match body.is_empty() {
+            true => EMPTY_SHA256.to_string(),
+            false => format!("{:x}", Sha256::digest(body)),
+        };
+        let time = Utc::now();
+        let mut signed = vec![
+            ("host".to_string(), host.clone()),
+            ("x-amz-content-sha256".to_string(), payload_hash.clone()),
+            (
+                "x-amz-date".to_string(),
+                time.format("%Y%m%dT%H%M%SZ").to_string(),
+            ),
+        ];
+        signed.extend(
+            headers
+                .iter()
+                .map(|(k, v)| (k.to_ascii_lowercase(), v.to_string())),
+        );
+        signed.sort();
+
+        let authorization = authorization(
+            &self.config,
+            method,
+            &uri,
+            &query,
+            &signed,
+            &payload_hash,
+            time,
+        );
+        let url = match query.is_empty() {
+            true => format!("{scheme}://{host}{uri}"),
+            false => format!("{scheme}://{host}{uri}?{query}"),
+        };
+        let mut request = self
+            .agent
+            .request(method, &url)
+            .set("Authorization", &authorization);
+        for (k, v) in signed.iter().filter(|(k, _)| k != "host") {
+            request = request.set(k, v);
+        }
+        match body.is_empty() {
+            true => request.call(),
+            false => request.send_bytes(body),
+        }
+        .map_err(_error)
+    }
+
+    /// Uploads the file at `path` to `object`, storing `sha256` with it. The file is read
+    /// through the rate limiter and must still have that hash.
+    pub fn put(&self, object: &S3Path, path: &Path, sha256: &str) -> io::Result<()> {
+        let mut reader = Throttled(File::open(path)?);
+        let size = reader.0.metadata()?.len();
+        let changed = || io::Error::other("the file changed while it was uploaded");
+
+        if size <= PART_SIZE {
+            let mut body = Vec::with_capacity(size as usize);
+            reader.read_to_end(&mut body)?;
+            if format!("{:x}", Sha256::digest(&body)) != sha256 {
+                return Err(changed());
+            }
+            self._request("PUT", object, &[], &[(SHA256_HEADER, sha256)], &body)?;
+            return Ok(());
+        }
+
+        let response = self._request(
+            "POST",
+            object,
+            &[("uploads", "")],
+            &[(SHA256_HEADER, sha256)],
+            &[],
+        )?;
+        let body = response.into_string()?;
+        let upload_id = _xml_value(&body, "UploadId")
+            .ok_or_else(|| io::Error::other("the server didn't start the upload"))?
+            .to_string();
+
+        let result = self._put_parts(object, &mut reader, size, sha256, &upload_id);
+        if result.is_err() {
+            let _ = self._request("DELETE", object, &[("uploadId", &upload_id)], &[], &[]);
+        }
+        result.map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidData => changed(),
+            _ => e,
+        })
+    }
+
+    fn _put_parts(
+        &self,
+        object: &S3Path,
+        reader: &mut impl Read,
+        size: u64,
+        sha256: &str,
+        upload_id: &str,
+    ) -> io::Result<()> {
+        let part_size = PART_SIZE.max(size.div_ceil(MAX_PARTS));
+        let mut hasher = Sha256::new();
+        let mut parts = String::new();
+        let mut buf = Vec::with_capacity(part_size as usize);
+        for number in 1.. {
+            buf.clear();
+            reader.take(part_size).read_to_end(&mut buf)?;
+            if buf.is_empty() {
+                break;
+            }
+            hasher.update(&buf);
+            let response = self._request(
+                "PUT",
+                object,
+                &[("partNumber", &number.to_string()), ("uploadId", upload_id)],
+                &[],
+                &buf,
+            )?;
+            let etag = response
+                .header("ETag")
+                .ok_or_else(|| io::Error::other("the server didn't return an ETag"))?;
+            parts.push_str(&format!(
+                "<Part><PartNumber>{number}</PartNumber><ETag>{etag}</ETag></Part>"
+            ));
+        }
+        if format!("{:x}", hasher.finalize()) != sha256 {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+
+        let body = format!("<CompleteMultipartUpload>{parts}</CompleteMultipartUpload>");
+        let response = self._request(
+            "POST",
+            object,
+            &[("uploadId", upload_id)],
+            &[],
+            body.as_bytes(),
+        )?;
+        // Completing can fail after the response started, which is reported in the body.
+        let body = response.into_string()?;
+        match _xml_value(&body, "Code") {
+            Some(code) => Err(io::Error::other(code.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns a reader over the contents of `object`.
+    pub fn open(&self, object: &S3Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(self._request("GET", object, &[], &[], &[])?.into_reader())
+    }
+
+    /// Returns the size of `object` and the hash uploaded with it, or `None` if it doesn't
+    /// exist.
+    pub fn head(&self, object: &S3Path) -> io::Result<Option<Stat>> {
+        match self._request("HEAD", object, &[], &[], &[]) {
+            Ok(response) => Ok(Some(Stat {
+                size: response
+                    .header("Content-Length")
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or_default(),
+                mtime: None,
+                sha256: response.header(SHA256_HEADER).map(str::to_string),
+            })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn delete_object(&self, object: &S3Path) -> io::Result<()> {
+        self._request("DELETE", object, &[], &[], &[])?;
+        Ok(())
+    }
+}
+
+fn _object(path: &Path) -> io::Result<S3Path> {
+    S3Path::parse(path).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:#?} isn't an S3 object", path),
+        )
+    })
+}
+
+impl StorageBackend for Client {
+    fn put(&self, path: &Path, reader: &mut dyn Read) -> io::Result<u64> {
+        // The hash has to be sent before the data, so it's spooled to a local file first.
+        let temp = std::env::temp_dir().join(format!(
+            "hardcpy-{}-{}",
+            std::process::id(),
+            Utc::now().timestamp_nanos_opt().unwrap_or_default()
+        ));
+        let result = File::create(&temp)
+            .and_then(|mut file| io::copy(reader, &mut file))
+            .and_then(|_| self.put_file(&temp, path));
+        let _ = fs::remove_file(&temp);
+        result.map(|(n, _)| n)
+    }
+
+    fn put_file(&self, from: &Path, path: &Path) -> io::Result<(u64, CopyMethod)> {
+        let object = _object(path)?;
+        let file = File::open(from)?;
+        let size = file.metadata()?.len();
+        let sha256 = _hash_reader(&mut Throttled(file))?;
+        self.put(&object, from, &sha256)?;
+        Ok((size, CopyMethod::Buffered))
+    }
+
+    fn get(&self, path: &Path) -> io::Result<Box<dyn Read + Send + '_>> {
+        self.open(&_object(path)?)
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Option<Stat>> {
+        self.head(&_object(path)?)
+    }
+
+    fn list(&self, prefix: &Path) -> io::Result<Vec<PathBuf>> {
+        let prefix = _object(prefix)?;
+        let bucket = S3Path {
+            bucket: prefix.bucket.clone(),
+            key: String::new(),
+        };
+        let key_prefix = match prefix.key.is_empty() {
+            true => String::new(),
+            false => format!("{}/", prefix.key),
+        };
+        let mut objects = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", key_prefix.as_str())];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let body = self
+                ._request("GET", &bucket, &query, &[], &[])?
+                .into_string()?;
+            let mut rest = body.as_str();
+            while let Some(key) = _xml_value(rest, "Key") {
+                objects.push(PathBuf::from(format!(
+                    "s3://{}/{}",
+                    prefix.bucket,
+                    _xml_unescape(key)
+                )));
+                rest = &rest[rest.find("</Key>").unwrap() + 6..];
+            }
+            token = match _xml_value(&body, "IsTruncated") {
+                Some("true") => _xml_value(&body, "NextContinuationToken").map(_xml_unescape),
+                _ => None,
+            };
+            if token.is_none() {
+                break;
+            }
+        }
+        objects.sort();
+        Ok(objects)
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        self.delete_object(&_object(path)?)
+    }
+}
+
+/// Connects to the service holding `location`, printing why if it can't.
+pub fn connect(location: &S3Path) -> Option<Client> {
+    match Config::load(&location.bucket) {
+        Ok(config) => Some(Client::new(config)),
+        Err(e) => {
+            eprintln!("{} {}", "Error:".red().bold(), e);
+            None
+        }
+    }
+}
+
+/// Returns the flags set in `opts` that S3 destinations have no use for. Files are uploaded
+/// as they are, and no manifest is written next to them.
+pub fn ignored_options(opts: &CopyOptions) -> Vec<&'static str> {
+    [
+        (opts.fsync, "--fsync"),
+        (opts.delta, "--delta"),
+        (opts.reflink != Reflink::Never, "--reflink"),
+        (opts.no_manifest, "--no-manifest"),
+        (opts.format != Format::Dir, "--format"),
+    ]
+    .into_iter()
+    .filter_map(|(set, name)| set.then_some(name))
+    .collect()
+}
@@ -0,0 +1,351 @@
+//! Where backups are stored. Backups into a directory or an S3 bucket are copied, verified,
+//! restored and deleted through [`StorageBackend`], so those work the same on both. Archives
+//! and backups sent to a remote peer have formats of their own and are handled by
+//! [`crate::archive`] and [`crate::remote`] instead.
+
+use crate::copy::{self, CopyMethod, Reflink};
+use crate::metadata::mtime_ns;
+use crate::ratelimit::Throttled;
+use crate::s3::{self, S3Path};
+use crate::{_hash_reader, _load_files, _temp_path, BackupEntry, CopyOptions, FileEntry};
+use colored::Colorize;
+use indicatif::{HumanCount, MultiProgress};
+use indicatif_log_bridge::LogWrapper;
+use log::{error, info};
+use rusqlite::Connection;
+#[cfg(test)]
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+#[cfg(test)]
+use std::io::Cursor;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+#[cfg(test)]
+use std::sync::Mutex;
+
+/// Size and modification time of a stored file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stat {
+    pub size: u64,
+    /// Unset if the backend doesn't track modification times.
+    pub mtime: Option<i64>,
+    /// The hex encoded SHA-256 hash stored along with the file, for backends that keep one.
+    pub sha256: Option<String>,
+}
+
+/// A place files can be stored in. Paths are the destination paths recorded in the catalog.
+pub trait StorageBackend: Send + Sync {
+    /// Stores everything read from `reader` at `path`, replacing it atomically. Returns the
+    /// number of bytes stored.
+    fn put(&self, path: &Path, reader: &mut dyn Read) -> io::Result<u64>;
+
+    /// Stores the local file `from` at `path`. Backends that can copy files faster than by
+    /// reading them override this.
+    fn put_file(&self, from: &Path, path: &Path) -> io::Result<(u64, CopyMethod)> {
+        let n = self.put(path, &mut Throttled(File::open(from)?))?;
+        Ok((n, CopyMethod::Buffered))
+    }
+
+    fn get(&self, path: &Path) -> io::Result<Box<dyn Read + Send + '_>>;
+
+    /// Returns `None` if nothing is stored at `path`.
+    fn stat(&self, path: &Path) -> io::Result<Option<Stat>>;
+
+    /// Returns every file below `prefix`, sorted.
+    fn list(&self, prefix: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Deletes the file at `path`.
+    fn delete(&self, path: &Path) -> io::Result<()>;
+
+    /// Removes `dir` and its parents up to and including `root` for as long as they're empty.
+    /// Backends without directories don't have anything to do.
+    fn remove_empty_dirs(&self, _dir: &Path, _root: &Path) {}
+
+    /// Returns the hex encoded SHA-256 hash of the file at `path`.
+    fn hash(&self, path: &Path) -> io::Result<String> {
+        _hash_reader(&mut self.get(path)?)
+    }
+}
+
+/// Returns the backend `backup` is stored in. Archives and remote peers aren't accessed
+/// through a backend.
+pub fn open(backup: &BackupEntry) -> Option<Box<dyn StorageBackend>> {
+    match S3Path::parse(&backup.to) {
+        Some(location) => Some(Box::new(s3::connect(&location)?)),
+        None => Some(Box::new(Local::default())),
+    }
+}
+
+/// The local filesystem, including mounted network shares.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Local {
+    reflink: Reflink,
+    /// Whether files are flushed to disk before they're moved into place.
+    fsync: bool,
+}
+
+impl Local {
+    pub fn new(opts: &CopyOptions) -> Self {
+        Self {
+            reflink: opts.reflink,
+            fsync: opts.fsync,
+        }
+    }
+
+    /// Writes `path` through a temporary file with `write`, so it's never left partially
+    /// written.
+    pub fn write_atomic<T>(
+        &self,
+        path: &Path,
+        write: impl FnOnce(&Path) -> io::Result<T>,
+    ) -> io::Result<T> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temp = _temp_path(path);
+        let result = write(&temp).and_then(|v| {
+            if self.fsync {
+                File::open(&temp)?.sync_all()?;
+            }
+            fs::rename(&temp, path)?;
+            if self.fsync {
+                if let Some(dir) = path.parent() {
+                    // Directories can't be opened for syncing on Windows.
+                    let _ = File::open(dir).and_then(|d| d.sync_all());
+                }
+            }
+            Ok(v)
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result
+    }
+}
+
+fn _list_dir(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            _list_dir(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+impl StorageBackend for Local {
+    fn put(&self, path: &Path, reader: &mut dyn Read) -> io::Result<u64> {
+        self.write_atomic(path, |temp| io::copy(reader, &mut File::create(temp)?))
+    }
+
+    fn put_file(&self, from: &Path, path: &Path) -> io::Result<(u64, CopyMethod)> {
+        let result = self.write_atomic(path, |temp| copy::copy(from, temp, self.reflink));
+        if let Ok((_, method)) = &result {
+            info!("{} {:#?} ({})", "Copied".green().bold(), path, method);
+        }
+        result
+    }
+
+    fn get(&self, path: &Path) -> io::Result<Box<dyn Read + Send + '_>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Option<Stat>> {
+        match fs::metadata(path) {
+            Ok(meta) => Ok(Some(Stat {
+                size: meta.len(),
+                mtime: Some(mtime_ns(&meta)),
+                sha256: None,
+            })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn list(&self, prefix: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        match _list_dir(prefix, &mut files) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_empty_dirs(&self, dir: &Path, root: &Path) {
+        let mut dir = Some(dir);
+        while let Some(v) = dir.filter(|v| v.starts_with(root)) {
+            // Fails once the directory isn't empty.
+            if fs::remove_dir(v).is_err() {
+                break;
+            }
+            dir = v.parent();
+        }
+    }
+}
+
+/// Keeps files in memory, for tests.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct Memory {
+    files: Mutex<BTreeMap<PathBuf, Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl StorageBackend for Memory {
+    fn put(&self, path: &Path, reader: &mut dyn Read) -> io::Result<u64> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let n = data.len() as u64;
+        self.files.lock().unwrap().insert(path.to_path_buf(), data);
+        Ok(n)
+    }
+
+    fn get(&self, path: &Path) -> io::Result<Box<dyn Read + Send + '_>> {
+        match self.files.lock().unwrap().get(path) {
+            Some(data) => Ok(Box::new(Cursor::new(data.clone()))),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{:#?} doesn't exist", path),
+            )),
+        }
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Option<Stat>> {
+        Ok(self.files.lock().unwrap().get(path).map(|data| Stat {
+            size: data.len() as u64,
+            mtime: None,
+            sha256: None,
+        }))
+    }
+
+    fn list(&self, prefix: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self
+            .files
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|v| v.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        match self.files.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+}
+
+/// Checks the stored copy of `entry` against the catalog and copies the source again if
+/// it's missing or doesn't match. Returns whether it was copied.
+pub fn verify_entry(backend: &dyn StorageBackend, entry: &FileEntry) -> io::Result<bool> {
+    info!("{} \"{}\"", "Verifying".green().bold(), entry.to.display());
+    let intact = match backend.stat(&entry.to)? {
+        // A hash stored along with the file has to match as well as the contents.
+        Some(stat) if stat.sha256.as_ref().is_some_and(|v| *v != entry.sha256) => false,
+        Some(_) => backend.hash(&entry.to)? == entry.sha256,
+        None => false,
+    };
+    if !intact {
+        info!("\n{} \"{}\"", "Copying".green().bold(), entry.to.display());
+        backend.put_file(&entry.from, &entry.to)?;
+    }
+    Ok(!intact)
+}
+
+/// Copies every file of `backup` from the backend back to its source path. Files are
+/// written atomically and only once their hash matches the catalog.
+pub fn restore(conn: &Connection, backend: &dyn StorageBackend, backup: &BackupEntry) {
+    let multi = MultiProgress::new();
+    let logger = colog::default_builder().build();
+    let _ = LogWrapper::new(multi.clone(), logger).try_init();
+
+    let mut restored = 0u64;
+    let mut errors = Vec::new();
+    for entry in _load_files(conn, backup.id).unwrap() {
+        let result = Local::default().write_atomic(&entry.from, |temp| {
+            let hash = _hash_reader(&mut TeeReader {
+                inner: backend.get(&entry.to)?,
+                file: File::create(temp)?,
+            })?;
+            match hash == entry.sha256 {
+                true => Ok(()),
+                false => Err(io::Error::other("the stored copy doesn't match its hash")),
+            }
+        });
+        match result {
+            Ok(()) => {
+                info!("{} \"{}\"", "Restored".green().bold(), entry.from.display());
+                restored += 1;
+            }
+            Err(e) => {
+                let err = format!("Couldn't restore {:#?}: {e}\n", entry.from);
+                error!("{}", err);
+                errors.push(err);
+            }
+        }
+    }
+    println!(
+        "{} {} files. ({} errors occured)",
+        "Restored".green().bold(),
+        HumanCount(restored),
+        HumanCount(errors.len() as u64),
+    );
+}
+
+/// Deletes the files of `backup` from the backend, or the archives holding them. Only files
+/// tracked in the catalog are deleted, anything else in the destination is left alone.
+/// Returns the errors for the files that couldn't be deleted.
+pub fn delete(
+    conn: &Connection,
+    backend: &dyn StorageBackend,
+    backup: &BackupEntry,
+) -> Vec<String> {
+    let paths: BTreeSet<PathBuf> = _load_files(conn, backup.id)
+        .unwrap()
+        .into_iter()
+        .map(|v| v.archive.unwrap_or(v.to))
+        .collect();
+    let mut errors = Vec::new();
+    for path in paths {
+        info!("{} \"{}\"", "Deleting".green().bold(), path.display());
+        match backend.delete(&path) {
+            Ok(()) => {}
+            // Already gone, e.g. because an earlier attempt was interrupted.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                errors.push(format!("Couldn't delete {:#?}: {e}", path));
+                continue;
+            }
+        }
+        if let Some(dir) = path.parent() {
+            backend.remove_empty_dirs(dir, &backup.to);
+        }
+    }
+    errors
+}
+
+/// Writes everything read through it to `file`.
+struct TeeReader<R: Read> {
+    inner: R,
+    file: File,
+}
+
+impl<R: Read> Read for TeeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        io::Write::write_all(&mut self.file, &buf[..n])?;
+        Ok(n)
+    }
+}